@@ -0,0 +1,30 @@
+//! Compares the channels that can answer the requests of `get`. Every
+//! request creates one, sends one session through it and drops it.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::executor::block_on;
+
+fn reply_channel(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reply_channel");
+
+    group.bench_function("async_channel_bounded", |b| {
+        b.iter(|| {
+            let (sender, receiver) = async_channel::bounded::<String>(1);
+            sender.try_send(String::new()).unwrap();
+            block_on(receiver.recv()).unwrap()
+        })
+    });
+
+    group.bench_function("futures_oneshot", |b| {
+        b.iter(|| {
+            let (sender, receiver) = futures::channel::oneshot::channel::<String>();
+            sender.send(String::new()).unwrap();
+            block_on(receiver).unwrap()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, reply_channel);
+criterion_main!(benches);
@@ -0,0 +1,55 @@
+//! Measures how fast the session pool hands out and takes back sessions
+//! when many transactions compete for them. QLDB is replaced by a mock
+//! that answers every command at once, so only the pool is measured.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::future::join_all;
+use qldb::{QldbClient, Region};
+use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher};
+use rusoto_qldb_session::QldbSessionClient;
+
+// Commands only read the part of the result they need, so the same
+// response works to start sessions and transactions and to commit them.
+const CANNED_RESPONSE: &str = r#"{
+    "StartSession": { "SessionToken": "bench-session" },
+    "StartTransaction": { "TransactionId": "bench-transaction" },
+    "CommitTransaction": { "TransactionId": "bench-transaction" }
+}"#;
+
+const CONCURRENT_TRANSACTIONS: usize = 100;
+
+fn mocked_client(max_sessions: u16) -> QldbClient {
+    let session_client = QldbSessionClient::new_with(
+        MockRequestDispatcher::default().with_body(CANNED_RESPONSE),
+        MockCredentialsProvider,
+        Region::UsEast1,
+    );
+
+    async_std::task::block_on(
+        QldbClient::builder("bench-ledger", max_sessions)
+            .session_client(session_client)
+            .build(),
+    )
+    .expect("Cannot build the client")
+}
+
+fn acquire_release(c: &mut Criterion) {
+    let mut group = c.benchmark_group("acquire_release");
+
+    for max_sessions in [1, 10, 100] {
+        let client = mocked_client(max_sessions);
+
+        group.bench_with_input(BenchmarkId::from_parameter(max_sessions), &client, |b, client| {
+            b.iter(|| {
+                async_std::task::block_on(join_all(
+                    (0..CONCURRENT_TRANSACTIONS).map(|_| client.transaction_within(|_| async { Ok(()) })),
+                ))
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, acquire_release);
+criterion_main!(benches);
@@ -0,0 +1,123 @@
+//! Synchronous version of the client, for scripts and CLI tools that don't
+//! have an async runtime. The session pool still runs in its own thread,
+//! the methods here only block the current thread until it answers.
+//!
+//! ```rust,no_run
+//! use qldb::blocking::QldbClient;
+//! use qldb::ion::IonValue;
+//! # use eyre::Result;
+//!
+//! # fn test() -> Result<()> {
+//! let client = QldbClient::default("rust-crate-test", 10)?;
+//!
+//! client.transaction_within(|transaction| {
+//!     transaction.execute("INSERT INTO TestTable VALUE ?", &[IonValue::String("test".to_string())])?;
+//!     Ok(())
+//! })?;
+//!
+//! let documents = client.read_query("SELECT * FROM TestTable", &[])?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Don't use it from async code, as it blocks the executor thread.
+
+use crate::{DocumentCollection, PoolStats, QldbResult};
+use futures::executor::block_on;
+use ion_binary_rs::IonValue;
+use std::time::Duration;
+
+/// Same as `qldb::QldbClient` but its methods block until they finish.
+#[derive(Clone)]
+pub struct QldbClient {
+    client: crate::QldbClient,
+}
+
+impl QldbClient {
+    /// Creates a new client, as `qldb::QldbClient::default` does.
+    pub fn default(ledger_name: &str, max_sessions: u16) -> QldbResult<QldbClient> {
+        let client = block_on(crate::QldbClient::default(ledger_name, max_sessions))?;
+
+        Ok(QldbClient { client })
+    }
+
+    /// Wraps a client created with the builder, e.g. with
+    /// `block_on(QldbClient::builder(..).build())`. It needs to use the
+    /// session pool thread, `build_with_spawner` won't work here.
+    pub fn from_async(client: crate::QldbClient) -> QldbClient {
+        QldbClient { client }
+    }
+
+    /// The async client behind this one.
+    pub fn as_async(&self) -> &crate::QldbClient {
+        &self.client
+    }
+
+    /// Runs the closure in a transaction that is committed if it returns
+    /// `Ok` and rolled back otherwise, as `transaction_within` does.
+    pub fn transaction_within<F, R>(&self, clousure: F) -> QldbResult<R>
+    where
+        F: FnOnce(&Transaction) -> QldbResult<R>,
+    {
+        let transaction = Transaction {
+            transaction: block_on(self.client.transaction())?,
+        };
+
+        match clousure(&transaction) {
+            Ok(result) => match block_on(transaction.transaction.commit()) {
+                Ok(_) => Ok(result),
+                Err(error) => {
+                    // Aborting it gives the session back to the pool
+                    let _ = block_on(transaction.transaction.silent_rollback());
+                    Err(error)
+                }
+            },
+            Err(error) => {
+                block_on(transaction.transaction.silent_rollback())?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Runs a read only statement, as `read_query` does.
+    pub fn read_query(&self, statement: &str, params: &[IonValue]) -> QldbResult<DocumentCollection> {
+        block_on(async { self.client.read_query(statement).await?.params(params).execute().await })
+    }
+
+    pub fn pool_stats(&self) -> QldbResult<PoolStats> {
+        block_on(self.client.pool_stats())
+    }
+
+    pub fn is_saturated(&self) -> QldbResult<bool> {
+        block_on(self.client.is_saturated())
+    }
+
+    pub fn last_creation_error(&self) -> Option<String> {
+        self.client.last_creation_error()
+    }
+
+    pub fn health_check(&self, timeout: Duration) -> QldbResult<()> {
+        block_on(self.client.health_check(timeout))
+    }
+
+    /// Closes the session pool, see `qldb::QldbClient::close`.
+    pub fn close(&mut self) -> QldbResult<()> {
+        block_on(self.client.close())
+    }
+}
+
+/// A transaction given to `QldbClient::transaction_within`.
+pub struct Transaction {
+    transaction: crate::Transaction,
+}
+
+impl Transaction {
+    /// Runs a statement in the transaction and returns its documents.
+    pub fn execute(&self, statement: &str, params: &[IonValue]) -> QldbResult<DocumentCollection> {
+        block_on(self.transaction.query(statement).params(params).execute())
+    }
+
+    pub fn ledger_name(&self) -> &str {
+        self.transaction.ledger_name()
+    }
+}
@@ -1,13 +1,22 @@
 #[cfg(feature = "internal_pool_with_spawner")]
-use crate::session_pool::{SpawnerFnMonoMultithread, SpawnerSessionPool};
+use crate::session_pool::SpawnerFnMonoMultithread;
 use crate::{
-    session_pool::{SessionPool, ThreadedSessionPool},
-    QldbError, QldbResult, QueryBuilder, Transaction,
+    client_builder::{validate_ledger_name, validate_max_sessions},
+    instrument::{traced_backoff, traced_command},
+    session_pool::{DynQldbSession, PoolStats, PooledSession, Session, SessionPool, SessionPoolFactory},
+    Backoff, PoolEvent, Priority, QldbClientBuilder, QldbError, QldbResult, QueryBuilder, Transaction,
 };
-use rusoto_core::{credential::ChainProvider, request::HttpClient, Region};
-use rusoto_qldb_session::QldbSessionClient;
+use async_compat::CompatExt;
+use async_io::Timer;
+use futures::future::{join_all, select, Either};
+use futures::Stream;
+use rusoto_qldb_session::SendCommandRequest;
 use std::future::Future;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Same as the official drivers.
+pub(crate) const DEFAULT_MAX_OCC_RETRIES: u32 = 4;
 
 /// It allows to start transactions. In QLDB all queries are transactions.
 /// So you always need to create a transaction for every query.
@@ -15,12 +24,60 @@ use std::sync::Arc;
 /// The recommended method is `transaction_within`.
 #[derive(Clone)]
 pub struct QldbClient {
-    client: Arc<QldbSessionClient>,
+    client: Arc<DynQldbSession>,
     _ledger_name: String,
     session_pool: Arc<dyn SessionPool>,
+    max_occ_retries: u32,
+    backoff: Arc<dyn Backoff>,
+    pool_factory: SessionPoolFactory,
 }
 
 impl QldbClient {
+    pub(crate) fn new(
+        client: Arc<DynQldbSession>,
+        ledger_name: &str,
+        session_pool: Arc<dyn SessionPool>,
+        max_occ_retries: u32,
+        backoff: Arc<dyn Backoff>,
+        pool_factory: SessionPoolFactory,
+    ) -> QldbClient {
+        QldbClient {
+            client,
+            _ledger_name: ledger_name.to_string(),
+            session_pool,
+            max_occ_retries,
+            backoff,
+            pool_factory,
+        }
+    }
+
+    /// Returns a client for another ledger, e.g. when every tenant of a
+    /// service has its own ledger. It has its own session pool, configured
+    /// as the pool of this client, but it shares the connection to QLDB and,
+    /// with `build`, the session pool thread. So there is no new thread for
+    /// every ledger.
+    ///
+    /// Every call creates a new session pool, so keep the returned client
+    /// for as long as the ledger is used. Closing it only closes its pool.
+    pub fn for_ledger(&self, ledger_name: &str) -> QldbResult<QldbClient> {
+        validate_ledger_name(ledger_name)?;
+
+        Ok(QldbClient::new(
+            self.client.clone(),
+            ledger_name,
+            (self.pool_factory)(ledger_name),
+            self.max_occ_retries,
+            self.backoff.clone(),
+            self.pool_factory.clone(),
+        ))
+    }
+
+    /// Returns a builder that allows to configure the client and its
+    /// session pool before creating it.
+    pub fn builder(ledger_name: &str, max_sessions: u16) -> QldbClientBuilder {
+        QldbClientBuilder::new(ledger_name, max_sessions)
+    }
+
     /// Creates a new QldbClient.
     ///
     /// It will spawn one thread for the session pool.
@@ -41,28 +98,13 @@ impl QldbClient {
     /// variable. If that is malformed of absent it will fall back on Region::UsEast1
     #[cfg(feature = "internal_pool_with_thread")]
     pub async fn default(ledger_name: &str, max_sessions: u16) -> QldbResult<QldbClient> {
-        let region = Region::default();
-
-        let credentials = ChainProvider::default();
-
-        // TODO: Map error correctly
-        let http_client = HttpClient::new()?;
-
-        let client = Arc::new(QldbSessionClient::new_with(http_client, credentials, region));
-
-        let session_pool = Arc::new(ThreadedSessionPool::new(client.clone(), ledger_name, max_sessions));
-
-        Ok(QldbClient {
-            client,
-            _ledger_name: ledger_name.to_string(),
-            session_pool,
-        })
+        QldbClient::builder(ledger_name, max_sessions).build().await
     }
 
     /// Creates a new QldbClient.
     ///
     /// This function won't spawn a thread for the session pool, but it will require
-    /// to be given an spawn function so it can start 2 green threads for the session
+    /// to be given an spawn function so it can start 3 green threads for the session
     /// pool.
     ///
     /// This function will take the credentials from several locations in this order:
@@ -85,27 +127,9 @@ impl QldbClient {
         max_sessions: u16,
         spawner: SpawnerFnMonoMultithread,
     ) -> QldbResult<QldbClient> {
-        let region = Region::default();
-
-        let credentials = ChainProvider::default();
-
-        // TODO: Map error correctly
-        let http_client = HttpClient::new()?;
-
-        let client = Arc::new(QldbSessionClient::new_with(http_client, credentials, region));
-
-        let session_pool = Arc::new(SpawnerSessionPool::new(
-            client.clone(),
-            ledger_name,
-            max_sessions,
-            spawner,
-        ));
-
-        Ok(QldbClient {
-            client,
-            _ledger_name: ledger_name.to_string(),
-            session_pool,
-        })
+        QldbClient::builder(ledger_name, max_sessions)
+            .build_with_spawner(spawner)
+            .await
     }
 
     /// Shorthand method that creates a transaction and executes a query.
@@ -116,6 +140,11 @@ impl QldbClient {
     ///
     /// This is a good option when you want to execute an isolated non-ACID
     /// SELECT/COUNT statement.
+    ///
+    /// As the transaction is never committed it is cheaper than a normal one:
+    /// it doesn't need to calculate the commit digest and it cannot fail due
+    /// to OCC conflicts. QLDB doesn't have a read only mode for transactions,
+    /// so it still needs to start one.
     pub async fn read_query(&self, statement: &str) -> QldbResult<QueryBuilder> {
         let transaction = self.auto_rollback_transaction().await?;
 
@@ -130,26 +159,344 @@ impl QldbClient {
     /// Use this method if you really need to use the transaction handler
     /// directly. If not, you may be better off using the method
     /// `transaction_within`.
+    ///
+    /// The session is checked with QLDB when the transaction starts. If QLDB
+    /// already expired it, the session is discarded and replaced by one that
+    /// QLDB confirms is alive, so a stale session never reaches the
+    /// statements of the transaction.
     pub async fn transaction(&self) -> QldbResult<Transaction> {
-        let session = self.session_pool.get().await.map_err(QldbError::SessionPoolClosed)?;
+        self.transaction_for_attempt(1).await
+    }
+
+    async fn transaction_for_attempt(&self, attempt: u32) -> QldbResult<Transaction> {
+        let session = self
+            .session_pool
+            .get_guard()
+            .await
+            .map_err(QldbError::from_session_pool)?;
+
+        Transaction::for_attempt(self.client.clone(), self.session_pool.clone(), session, false, attempt).await
+    }
+
+    /// Same as `transaction` but, when all the sessions are in use, it gets
+    /// one before the requests with a lower priority, see `Priority`.
+    pub async fn transaction_with_priority(&self, priority: Priority) -> QldbResult<Transaction> {
+        let session = self
+            .session_pool
+            .get_with_priority(priority)
+            .await
+            .map_err(QldbError::from_session_pool)?;
+
+        let session = PooledSession::new(session, self.session_pool.clone());
 
         Transaction::new(self.client.clone(), self.session_pool.clone(), session, false).await
     }
 
+    /// Same as `transaction` but it fails with `QldbError::SessionPoolTimeout`
+    /// if the session pool cannot provide a session within `timeout`. This
+    /// happens when all the sessions are in use and the pool is already at
+    /// its maximum size.
+    pub async fn transaction_with_timeout(&self, timeout: Duration) -> QldbResult<Transaction> {
+        let session = self
+            .session_pool
+            .get_with_timeout(timeout)
+            .await
+            .map_err(QldbError::from_session_pool)?
+            .ok_or(QldbError::SessionPoolTimeout)?;
+
+        let session = PooledSession::new(session, self.session_pool.clone());
+
+        Transaction::new(self.client.clone(), self.session_pool.clone(), session, false).await
+    }
+
+    /// Starts a transaction only if the session pool has an idle session
+    /// ready to be used. It returns `Ok(None)` instead of waiting or
+    /// creating a new session, which is useful for latency-sensitive paths
+    /// that have an alternative way to get the data.
+    pub async fn try_transaction(&self) -> QldbResult<Option<Transaction>> {
+        let session = match self
+            .session_pool
+            .try_get()
+            .await
+            .map_err(QldbError::from_session_pool)?
+        {
+            Some(session) => PooledSession::new(session, self.session_pool.clone()),
+            None => return Ok(None),
+        };
+
+        let transaction = Transaction::new(self.client.clone(), self.session_pool.clone(), session, false).await?;
+
+        Ok(Some(transaction))
+    }
+
+    /// Starts `n` transactions at once, each one on its own session, e.g.
+    /// to spread the workers of a bulk import across them. If one of them
+    /// cannot be started the others are rolled back and the error is
+    /// returned, so no session is kept out of the pool.
+    ///
+    /// Asking for more than `max_sessions` makes the remaining ones wait
+    /// until other transactions finish, as with `transaction`.
+    pub async fn transactions(&self, n: usize) -> QldbResult<Vec<Transaction>> {
+        let sessions = self
+            .session_pool
+            .get_many(n)
+            .await
+            .map_err(QldbError::from_session_pool)?;
+
+        let results = join_all(sessions.into_iter().map(|session| {
+            let session = PooledSession::new(session, self.session_pool.clone());
+
+            Transaction::new(self.client.clone(), self.session_pool.clone(), session, false)
+        }))
+        .await;
+
+        let mut transactions = Vec::with_capacity(n);
+        let mut error = None;
+
+        for result in results {
+            match result {
+                Ok(transaction) => transactions.push(transaction),
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+
+        match error {
+            Some(err) => {
+                for transaction in transactions {
+                    let _ = transaction.rollback().await;
+                }
+
+                Err(err)
+            }
+            None => Ok(transactions),
+        }
+    }
+
     pub(crate) async fn auto_rollback_transaction(&self) -> QldbResult<Transaction> {
-        let session = self.session_pool.get().await.map_err(QldbError::SessionPoolClosed)?;
+        let session = self.session_pool.get().await.map_err(QldbError::from_session_pool)?;
+
+        let session = PooledSession::new(session, self.session_pool.clone());
 
         Transaction::new(self.client.clone(), self.session_pool.clone(), session, true).await
     }
 
+    /// Returns a snapshot of the session pool state: how many sessions are
+    /// open, how many are idle and how many requests are waiting for a
+    /// session. It fails if the session pool was closed.
+    pub async fn pool_stats(&self) -> QldbResult<PoolStats> {
+        self.session_pool.stats().await.map_err(QldbError::from_session_pool)
+    }
+
+    /// Whether a transaction started now would have to wait for a session,
+    /// as all of them are in use and the pool is at its max sessions. It comes
+    /// from a single `pool_stats` snapshot, e.g. for load shedding before
+    /// starting a transaction. It fails if the session pool was closed.
+    pub async fn is_saturated(&self) -> QldbResult<bool> {
+        Ok(self.pool_stats().await?.is_saturated())
+    }
+
+    /// Why the session pool cannot create sessions, e.g. for a health check
+    /// to report whether QLDB is throttling, the credentials are wrong or the
+    /// ledger doesn't exist. It has the error of the last failed creation,
+    /// with its AWS error code when there is one, and it is None once a
+    /// session is created again.
+    pub fn last_creation_error(&self) -> Option<String> {
+        self.session_pool.last_creation_error()
+    }
+
+    /// Returns the ids of the idle sessions in the pool, e.g. to match them
+    /// with the QLDB session metrics. Together with `pool_stats` it tells
+    /// which sessions are idle and how many are in use.
+    pub async fn idle_session_ids(&self) -> QldbResult<Vec<String>> {
+        self.session_pool
+            .idle_session_ids()
+            .await
+            .map_err(QldbError::from_session_pool)
+    }
+
+    /// Returns a stream with everything that happens in the session pool
+    /// from now on: sessions created, handed out, given back and closed,
+    /// requests that have to wait and creations that failed. E.g. to build
+    /// metrics or debug logs in a single place.
+    ///
+    /// Every stream gets all the events. A stream that falls too far behind
+    /// misses the newest ones instead of slowing down the pool.
+    pub fn subscribe(&self) -> impl Stream<Item = PoolEvent> {
+        self.session_pool.subscribe()
+    }
+
+    /// Closes the idle sessions, e.g. to lower the number of open sessions
+    /// during off-peak hours, and returns how many were closed. Unlike
+    /// `close`, the client keeps working and opens new sessions as they are
+    /// needed. The sessions in use are not affected.
+    pub async fn drain_idle(&self) -> QldbResult<usize> {
+        self.session_pool
+            .drain_idle()
+            .await
+            .map_err(QldbError::from_session_pool)
+    }
+
+    /// Returns how many sessions are open, both the ones in use and the
+    /// idle ones. It can go over `max_sessions` by up to the overflow allowed
+    /// with `QldbClientBuilder::allow_overflow`, and for a while after
+    /// lowering `max_sessions`, as the extra sessions are kept until they
+    /// expire.
+    pub async fn active_sessions(&self) -> QldbResult<u16> {
+        Ok(self.pool_stats().await?.active)
+    }
+
+    /// Returns the maximum number of sessions of the pool, as given when
+    /// creating the client or changed later with `set_max_sessions`.
+    pub async fn max_sessions(&self) -> QldbResult<u16> {
+        Ok(self.pool_stats().await?.max_sessions)
+    }
+
+    /// Returns how many transactions are waiting for a session because all
+    /// of them are in use. A number that keeps growing means that the pool
+    /// is saturated and `max_sessions` may need to be raised.
+    pub async fn pending_requests(&self) -> QldbResult<usize> {
+        self.session_pool
+            .pending_requests()
+            .await
+            .map_err(QldbError::from_session_pool)
+    }
+
+    /// Changes the maximum number of sessions of the pool without having
+    /// to create a new client, e.g. to handle a traffic spike. Lowering it
+    /// won't close the sessions in use, but no new sessions will be created
+    /// until the number of open sessions is under the new maximum. It
+    /// cannot be zero.
+    pub async fn set_max_sessions(&self, max_sessions: u16) -> QldbResult<()> {
+        validate_max_sessions(max_sessions)?;
+
+        self.session_pool
+            .set_max_sessions(max_sessions)
+            .await
+            .map_err(QldbError::from_session_pool)
+    }
+
+    /// Fills the pool until it has `target` sessions open, waiting for them
+    /// to be ready, e.g. to provision capacity before a service reports
+    /// itself as ready. The sessions in use count toward `target`, and only
+    /// the missing ones are created, without checking out any session.
+    /// Unlike `min_idle` it doesn't keep them, the pool closes them as usual
+    /// once they expire.
+    ///
+    /// It fails with `QldbError::SessionPoolTimeout` if the sessions are not
+    /// ready within `timeout`, and `target` cannot be over `max_sessions`.
+    pub async fn warm(&self, target: u16, timeout: Duration) -> QldbResult<()> {
+        let max_sessions = self.max_sessions().await?;
+
+        if target > max_sessions {
+            return Err(QldbError::InvalidPoolConfiguration(format!(
+                "cannot warm {} sessions, max_sessions is {}",
+                target, max_sessions
+            )));
+        }
+
+        let warmed = self
+            .session_pool
+            .warm(target, timeout)
+            .await
+            .map_err(QldbError::from_session_pool)?;
+
+        if warmed {
+            Ok(())
+        } else {
+            Err(QldbError::SessionPoolTimeout)
+        }
+    }
+
+    /// Checks that the client can actually talk to QLDB: it takes a session
+    /// from the pool and confirms with QLDB that the session is alive. Useful
+    /// for readiness probes, as it fails instead of hanging if it doesn't
+    /// finish within `timeout`, with `QldbError::SessionPoolTimeout` if no
+    /// session was available in time and `QldbError::HealthCheckTimeout` if
+    /// QLDB didn't answer.
+    pub async fn health_check(&self, timeout: Duration) -> QldbResult<()> {
+        let started_on = Instant::now();
+
+        let session = self
+            .session_pool
+            .get_with_timeout(timeout)
+            .await
+            .map_err(QldbError::from_session_pool)?
+            .ok_or(QldbError::SessionPoolTimeout)?;
+
+        let session = PooledSession::new(session, self.session_pool.clone());
+
+        let session_id = session.get_session_id().to_string();
+        // The guard is consumed below, while the command still reports to the observer
+        let checked_session = Session::clone(&session);
+
+        let checking = self
+            .client
+            .send_command(SendCommandRequest {
+                session_token: Some(session_id.clone()),
+                ..Default::default()
+            })
+            .compat();
+        let checking = traced_command(
+            "health_check",
+            Some(&session_id),
+            1,
+            checked_session.observer(),
+            checking,
+        );
+        futures::pin_mut!(checking);
+
+        match select(checking, Timer::after(timeout.saturating_sub(started_on.elapsed()))).await {
+            // A pool that cannot take the session back is not healthy either
+            Either::Left((Ok(_), _)) => self
+                .session_pool
+                .give_back_checked(session.into_session())
+                .await
+                .map_err(QldbError::from_session_pool),
+            Either::Left((Err(err), _)) => {
+                let err = QldbError::from(err);
+
+                if err.is_invalid_session() {
+                    self.session_pool.discard(session.into_session());
+                }
+
+                Err(err)
+            }
+            Either::Right(_) => Err(QldbError::HealthCheckTimeout),
+        }
+    }
+
     /// It closes the session pool. Current transaction which already have a
     /// session can work as normal, but new transaction (requiring a new session
     /// id) will return error.
     ///
+    /// It closes the idle sessions in QLDB and waits for the sessions in use
+    /// to be returned, closing them too, for as long as the close grace period
+    /// (30 seconds by default). It fails with `QldbError::SessionPoolCloseError`
+    /// if some sessions are still in use after that.
+    ///
+    /// The clients share the session pool with their clones, so closing
+    /// one closes all of them. Calling it again, from this client or from a
+    /// clone, waits for the first close to finish and returns its result.
+    ///
+    /// Dropping the last clone without calling it closes the pool in the
+    /// background, with no way to know how it went.
+    ///
     /// Call this method only when you are sure that all important work is
     /// already commited to QLDB.
-    pub async fn close(&mut self) {
-        self.session_pool.close().await;
+    pub async fn close(&mut self) -> QldbResult<()> {
+        self.session_pool
+            .close()
+            .await
+            .map_err(QldbError::SessionPoolCloseError)
+    }
+
+    /// Completes once the session pool starts closing, e.g. because another
+    /// clone of the client called `close`, so background tasks using the
+    /// client can stop instead of failing to get sessions.
+    pub async fn closed(&self) {
+        self.session_pool.closed().await
     }
 
     /// It call the closure providing an already made transaction. Once the
@@ -160,19 +507,63 @@ impl QldbClient {
         FR: Future<Output = QldbResult<R>>,
         F: FnOnce(Transaction) -> FR,
     {
-        let transaction = self.transaction().await?;
+        self.transaction_within_attempt(clousure, 1).await
+    }
+
+    /// `transaction_within` for the given try of `transact`, so its commands
+    /// are reported with it.
+    async fn transaction_within_attempt<F, R, FR>(&self, clousure: F, attempt: u32) -> QldbResult<R>
+    where
+        R: std::fmt::Debug,
+        FR: Future<Output = QldbResult<R>>,
+        F: FnOnce(Transaction) -> FR,
+    {
+        let transaction = self.transaction_for_attempt(attempt).await?;
 
         let result = clousure(transaction.clone()).await;
 
         match result {
-            Ok(result) => {
-                transaction.silent_commit().await?;
-                Ok(result)
-            }
+            Ok(result) => match transaction.silent_commit().await {
+                Ok(_) => Ok(result),
+                Err(error) => {
+                    // Aborting it gives the session back to the pool
+                    let _ = transaction.silent_rollback().await;
+                    Err(error)
+                }
+            },
             Err(error) => {
                 transaction.silent_rollback().await?;
                 Err(error)
             }
         }
     }
+
+    /// Same as `transaction_within` but, as QLDB uses optimistic concurrency
+    /// control, when QLDB rejects the transaction due to an OCC conflict it
+    /// runs the closure again in a new transaction. It does so as many times
+    /// as configured with `QldbClientBuilder::max_occ_retries` (4 by default).
+    ///
+    /// The closure may be called more than once, so it needs to be `FnMut`
+    /// and it shouldn't have side effects outside of the transaction.
+    pub async fn transact<F, R, FR>(&self, mut clousure: F) -> QldbResult<R>
+    where
+        R: std::fmt::Debug,
+        FR: Future<Output = QldbResult<R>>,
+        F: FnMut(Transaction) -> FR,
+    {
+        let mut tries: u32 = 0;
+
+        loop {
+            tries = tries.saturating_add(1);
+
+            match self.transaction_within_attempt(&mut clousure, tries).await {
+                Err(error) if error.is_occ_conflict() && tries <= self.max_occ_retries => {
+                    let delay = self.backoff.next_delay(tries);
+                    traced_backoff("transact", tries, delay);
+                    Timer::after(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
 }
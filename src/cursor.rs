@@ -1,5 +1,6 @@
 use crate::DocumentCollection;
-use crate::{QldbResult, QueryBuilder};
+use crate::{Document, QldbResult, QueryBuilder};
+use futures::stream::{self, Stream};
 use std::convert::TryInto;
 
 /// Cursor allows to get all values from a statement page by page.
@@ -99,7 +100,7 @@ impl Cursor {
         let mut result = DocumentCollection::new(vec![]);
 
         while let Some(values) = self.load_more().await? {
-            result.extend(values.into_iter());
+            result.extend(values);
 
             if self.next_page.is_none() {
                 break;
@@ -108,4 +109,44 @@ impl Cursor {
 
         Ok(result)
     }
+
+    /// Turns the cursor into a stream of documents. Pages are loaded as
+    /// the stream is consumed, so the next page is only requested once
+    /// all the documents of the current one have been taken. The stream
+    /// ends after the first error.
+    ///
+    /// ```rust,no_run
+    /// # use qldb::{Cursor, QldbResult};
+    /// use futures::StreamExt;
+    ///
+    /// # async fn test(cursor: Cursor) ->  QldbResult<()> {
+    ///     let documents = cursor.into_stream();
+    ///     futures::pin_mut!(documents);
+    ///
+    ///     while let Some(document) = documents.next().await {
+    ///         println!("{:?}", document?);
+    ///     }
+    ///
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn into_stream(self) -> impl Stream<Item = QldbResult<Document>> {
+        let page = DocumentCollection::default().into_iter();
+
+        stream::unfold(Some((self, page)), |state| async move {
+            let (mut cursor, mut page) = state?;
+
+            loop {
+                if let Some(document) = page.next() {
+                    return Some((Ok(document), Some((cursor, page))));
+                }
+
+                match cursor.load_more().await {
+                    Ok(Some(documents)) => page = documents.into_iter(),
+                    Ok(None) => return None,
+                    Err(err) => return Some((Err(err), None)),
+                }
+            }
+        })
+    }
 }
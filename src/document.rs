@@ -52,6 +52,11 @@ impl Document {
         }
     }
 
+    /// The whole document as an Ion struct.
+    pub(crate) fn to_ion_value(&self) -> IonValue {
+        IonValue::Struct(self.document.clone())
+    }
+
     /// Gets the raw IonValue
     pub fn get(&self, name: &str) -> Option<&IonValue> {
         self.document.get(name)
@@ -1,5 +1,10 @@
-use crate::{document::Document, types::QldbExtractError};
+use crate::{
+    document::Document,
+    ion_deserializer::IonDeserializer,
+    types::{QldbExtractError, QldbExtractResult},
+};
 use ion_binary_rs::IonValue;
+use serde::de::DeserializeOwned;
 use std::convert::TryFrom;
 use std::ops::Index;
 
@@ -77,6 +82,89 @@ impl DocumentCollection {
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Transforms every document into the requested type, usually your own
+    /// struct implementing `TryFrom<Document>` with `Document::get_value`.
+    /// It fails with the index of the first document that cannot be
+    /// transformed, instead of skipping it.
+    ///
+    /// ```rust,no_run
+    /// use qldb::{Document, DocumentCollection, QldbExtractError, QldbExtractResult};
+    /// use std::convert::TryFrom;
+    ///
+    /// struct Car {
+    ///     model: String,
+    ///     year: i64,
+    /// }
+    ///
+    /// impl TryFrom<Document> for Car {
+    ///     type Error = QldbExtractError;
+    ///
+    ///     fn try_from(document: Document) -> QldbExtractResult<Car> {
+    ///         Ok(Car {
+    ///             model: document.get_value("Model")?,
+    ///             year: document.get_value("Year")?,
+    ///         })
+    ///     }
+    /// }
+    ///
+    /// fn get_cars(documents: DocumentCollection) -> QldbExtractResult<Vec<Car>> {
+    ///     documents.extract::<Car>()
+    /// }
+    /// ```
+    pub fn extract<T>(self) -> QldbExtractResult<Vec<T>>
+    where
+        T: TryFrom<Document>,
+        <T as TryFrom<Document>>::Error: std::error::Error + Send + Sync + 'static,
+    {
+        self.documents
+            .into_iter()
+            .enumerate()
+            .map(|(index, document)| {
+                T::try_from(document).map_err(|err| QldbExtractError::BadDocument {
+                    index,
+                    source: Box::new(err),
+                })
+            })
+            .collect()
+    }
+
+    /// Same as `extract` but with serde, for types implementing
+    /// `Deserialize`, e.g. a struct deriving it. Ion timestamps are read as
+    /// RFC 3339 strings and decimals as their exact value in a string, which
+    /// is what `chrono::DateTime` and `bigdecimal::BigDecimal` read with
+    /// their `serde` feature. Decimals can be read as floats too.
+    ///
+    /// ```rust,no_run
+    /// use qldb::{DocumentCollection, QldbExtractResult};
+    /// use serde::Deserialize;
+    ///
+    /// #[derive(Deserialize)]
+    /// struct Car {
+    ///     #[serde(rename = "Model")]
+    ///     model: String,
+    ///     #[serde(rename = "Year")]
+    ///     year: i64,
+    /// }
+    ///
+    /// fn get_cars(documents: &DocumentCollection) -> QldbExtractResult<Vec<Car>> {
+    ///     documents.rows::<Car>()
+    /// }
+    /// ```
+    pub fn rows<T: DeserializeOwned>(&self) -> QldbExtractResult<Vec<T>> {
+        self.documents
+            .iter()
+            .enumerate()
+            .map(|(index, document)| {
+                T::deserialize(IonDeserializer::new(document.to_ion_value())).map_err(|err| {
+                    QldbExtractError::BadDocument {
+                        index,
+                        source: Box::new(err),
+                    }
+                })
+            })
+            .collect()
+    }
 }
 
 impl Default for DocumentCollection {
@@ -0,0 +1,95 @@
+//! Instrumentation of the QLDB round trips. The tracing part compiles to
+//! nothing when the `tracing` feature is not enabled.
+
+use crate::{CommandMetrics, SessionObserver};
+use std::fmt::Display;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// Runs a QLDB command, reporting how long it took to the observer and,
+/// with the `tracing` feature, inside a span with the session id and the
+/// attempt number.
+pub(crate) async fn traced_command<F, T, E>(
+    command: &'static str,
+    session_id: Option<&str>,
+    attempt: u32,
+    observer: &dyn SessionObserver,
+    future: F,
+) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Display,
+{
+    let started_on = Instant::now();
+
+    let result = traced(command, session_id, attempt, future).await;
+
+    observer.on_command(&CommandMetrics {
+        command,
+        latency: started_on.elapsed(),
+        attempt,
+        succeeded: result.is_ok(),
+    });
+
+    result
+}
+
+#[cfg(feature = "tracing")]
+async fn traced<F, T, E>(command: &'static str, session_id: Option<&str>, attempt: u32, future: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Display,
+{
+    use tracing::Instrument;
+
+    let span = tracing::debug_span!(
+        "qldb_command",
+        command,
+        session_id = tracing::field::Empty,
+        attempt,
+        latency_ms = tracing::field::Empty,
+    );
+
+    if let Some(session_id) = session_id {
+        span.record("session_id", session_id);
+    }
+
+    let started_on = Instant::now();
+
+    let result = future.instrument(span.clone()).await;
+
+    let latency_ms = started_on.elapsed().as_millis() as u64;
+    span.record("latency_ms", latency_ms);
+
+    let _entered = span.enter();
+
+    match &result {
+        Ok(_) => tracing::debug!(latency_ms, "QLDB command succeeded"),
+        Err(err) => tracing::warn!(latency_ms, error = %err, "QLDB command failed"),
+    }
+
+    result
+}
+
+#[cfg(not(feature = "tracing"))]
+async fn traced<F, T, E>(_command: &'static str, _session_id: Option<&str>, _attempt: u32, future: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Display,
+{
+    future.await
+}
+
+/// Records that a failed attempt is going to be retried after `delay`.
+#[cfg(feature = "tracing")]
+pub(crate) fn traced_backoff(operation: &'static str, attempt: u32, delay: Duration) {
+    tracing::debug!(
+        operation,
+        attempt,
+        delay_ms = delay.as_millis() as u64,
+        "Waiting before retrying"
+    );
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn traced_backoff(_operation: &'static str, _attempt: u32, _delay: Duration) {}
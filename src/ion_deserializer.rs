@@ -0,0 +1,222 @@
+//! A serde `Deserializer` over the Ion values returned by QLDB, so the
+//! documents can be read into any type implementing `Deserialize`.
+//!
+//! Ion has more types than serde. Timestamps are given as RFC 3339 strings
+//! and decimals as their exact value in a string, which is what
+//! `chrono::DateTime` and `bigdecimal::BigDecimal` read with their `serde`
+//! feature. Decimals can be read as floats too, losing precision.
+
+use ion_binary_rs::IonValue;
+use serde::de::{self, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess, Visitor};
+use serde::forward_to_deserialize_any;
+use std::collections::hash_map;
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Debug)]
+pub(crate) struct DeserializeError(String);
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DeserializeError {}
+
+impl de::Error for DeserializeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        DeserializeError(msg.to_string())
+    }
+}
+
+pub(crate) struct IonDeserializer(IonValue);
+
+impl IonDeserializer {
+    pub(crate) fn new(value: IonValue) -> IonDeserializer {
+        IonDeserializer(value)
+    }
+
+    /// The value without its annotations, which have no place in serde.
+    fn into_value(self) -> IonValue {
+        let mut value = self.0;
+
+        while let IonValue::Annotation(_, annotated) = value {
+            value = *annotated;
+        }
+
+        value
+    }
+}
+
+impl<'de> de::Deserializer<'de> for IonDeserializer {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        match self.into_value() {
+            IonValue::Null(_) => visitor.visit_unit(),
+            IonValue::Bool(value) => visitor.visit_bool(value),
+            IonValue::Integer(value) => visitor.visit_i64(value),
+            IonValue::BigInteger(value) => match (i64::try_from(&value), u64::try_from(&value)) {
+                (Ok(value), _) => visitor.visit_i64(value),
+                (_, Ok(value)) => visitor.visit_u64(value),
+                _ => visitor.visit_string(value.to_string()),
+            },
+            IonValue::Float(value) => visitor.visit_f64(value),
+            IonValue::Decimal(value) => visitor.visit_string(value.to_string()),
+            IonValue::DateTime(value) => visitor.visit_string(value.to_rfc3339()),
+            IonValue::String(value) | IonValue::Symbol(value) => visitor.visit_string(value),
+            IonValue::Clob(value) | IonValue::Blob(value) => visitor.visit_byte_buf(value),
+            IonValue::List(values) | IonValue::SExpr(values) => visitor.visit_seq(IonSeq(values.into_iter())),
+            IonValue::Struct(values) => visitor.visit_map(IonMap {
+                entries: values.into_iter(),
+                value: None,
+            }),
+            // Already removed by `into_value`
+            IonValue::Annotation(_, value) => IonDeserializer(*value).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        match self.into_value() {
+            IonValue::Decimal(value) => match value.to_string().parse() {
+                Ok(value) => visitor.visit_f64(value),
+                Err(_) => Err(de::Error::custom(format!("The decimal {} is not a float", value))),
+            },
+            value => IonDeserializer(value).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        match self.into_value() {
+            IonValue::Null(_) => visitor.visit_none(),
+            value => visitor.visit_some(IonDeserializer(value)),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeserializeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeserializeError> {
+        match self.into_value() {
+            // A unit variant is just its name
+            IonValue::String(variant) | IonValue::Symbol(variant) => {
+                visitor.visit_enum(IntoDeserializer::<DeserializeError>::into_deserializer(variant))
+            }
+            // The others are a struct with the variant as the only field
+            IonValue::Struct(values) if values.len() == 1 => {
+                let (variant, value) = values.into_iter().next().expect("The struct has one field");
+                visitor.visit_enum(IonEnum { variant, value })
+            }
+            value => Err(de::Error::custom(format!("Expected an enum, found {:?}", value))),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct IonSeq(std::vec::IntoIter<IonValue>);
+
+impl<'de> SeqAccess<'de> for IonSeq {
+    type Error = DeserializeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, DeserializeError> {
+        self.0
+            .next()
+            .map(|value| seed.deserialize(IonDeserializer(value)))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct IonMap {
+    entries: hash_map::IntoIter<String, IonValue>,
+    // The value of the last key read
+    value: Option<IonValue>,
+}
+
+impl<'de> MapAccess<'de> for IonMap {
+    type Error = DeserializeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, DeserializeError> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(IntoDeserializer::<DeserializeError>::into_deserializer(key))
+                    .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, DeserializeError> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(IonDeserializer(value)),
+            None => Err(de::Error::custom("A struct value was read before its field name")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+struct IonEnum {
+    variant: String,
+    value: IonValue,
+}
+
+impl<'de> EnumAccess<'de> for IonEnum {
+    type Error = DeserializeError;
+    type Variant = IonDeserializer;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, IonDeserializer), DeserializeError> {
+        let variant = seed.deserialize(IntoDeserializer::<DeserializeError>::into_deserializer(self.variant))?;
+
+        Ok((variant, IonDeserializer(self.value)))
+    }
+}
+
+impl<'de> VariantAccess<'de> for IonDeserializer {
+    type Error = DeserializeError;
+
+    fn unit_variant(self) -> Result<(), DeserializeError> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, DeserializeError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, DeserializeError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeserializeError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
@@ -56,9 +56,12 @@
 //! the spawn function of the executor you use. We tested async-std and
 //! tokio, but others should work as well.
 //!
-//! This pool will spawn two internal tasks handling the pool.
+//! This pool will spawn five internal tasks handling the pool (six if
+//! the keep alive is enabled), and sessions are closed in short lived
+//! tasks spawned with the same function.
 //!
-//! Use this if you want for this driver to not create a new thread.
+//! Use this if you want for this driver to not create a new thread, e.g. in
+//! services already running on tokio or with several clients.
 //!
 //! Example with async-std:
 //!
@@ -96,6 +99,28 @@
 //! # }
 //! ```
 //!
+//! The same works when configuring the client with the builder:
+//!
+//! ```rust,no_run
+//! use qldb::QldbClient;
+//! use std::sync::Arc;
+//! # use eyre::Result;
+//!
+//! # async fn test() -> Result<()> {
+//!     let client = QldbClient::builder("rust-crate-test", 200)
+//!         .min_idle(10)
+//!         .build_with_spawner(Arc::new(move |fut| {tokio::spawn(Box::pin(fut));}))
+//!         .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With the `async-std` feature, `QldbClientBuilder::build_on_async_std`
+//! does the same as the async-std example without giving it the spawner.
+//! ```toml,no_code
+//! qldb = { version = "3", features = ["async-std"]}
+//! ```
+//!
 //! ## Select the pool you want to use
 //!
 //! By default, both pools are available by using the methods `QldbClient::default`
@@ -113,6 +138,60 @@
 //! qldb = { version = "3", default_features = false, features = ["internal_pool_with_spawner"]}
 //! ```
 //!
+//! ## Many ledgers
+//!
+//! Every client has its own session pool, but the connection to QLDB can be
+//! shared. `QldbClient::for_ledger` returns a client for another ledger that
+//! reuses the connection of the original one and, when built with `build`,
+//! its session pool thread too.
+//!
+//! To share a connection you configured yourself, e.g. across regions
+//! or with a custom HTTP client, create the rusoto client once and give a
+//! clone of it to every builder. The clones of a `QldbSessionClient` share
+//! the same HTTP connection pool, so there is only one no matter how many
+//! session pools use it. The client is `Send` and `Sync`, so it is safe to
+//! use from all of them at once.
+//! ```rust,no_run
+//! use qldb::{QldbClient, Region};
+//! use rusoto_core::{credential::ChainProvider, request::HttpClient};
+//! use rusoto_qldb_session::QldbSessionClient;
+//! # use eyre::Result;
+//!
+//! # async fn test() -> Result<()> {
+//!     let session_client = QldbSessionClient::new_with(HttpClient::new()?, ChainProvider::default(), Region::EuWest1);
+//!
+//!     let orders = QldbClient::builder("orders", 50)
+//!         .session_client(session_client.clone())
+//!         .build()
+//!         .await?;
+//!
+//!     let invoices = QldbClient::builder("invoices", 50)
+//!         .session_client(session_client)
+//!         .build()
+//!         .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Blocking client
+//!
+//! With the `blocking` feature, `qldb::blocking::QldbClient` offers the same
+//! client for synchronous code, e.g. scripts and CLI tools without an async
+//! runtime. It uses the default session pool, the one with its own thread.
+//! ```toml,no_code
+//! qldb = { version = "3", features = ["blocking"]}
+//! ```
+//!
+//! ## Tracing
+//!
+//! With the `tracing` feature every command sent to QLDB is recorded as a
+//! [tracing](https://crates.io/crates/tracing) span, with the session id, the
+//! attempt number and its latency. The waits between retries are recorded as
+//! events. Without the feature there is no cost at all.
+//! ```toml,no_code
+//! qldb = { version = "3", features = ["tracing"]}
+//! ```
+//!
 //! # Underlying Ion Format Implementation
 //!
 //! The library uses [ion-binary-rs](https://crates.io/crates/ion-binary-rs),
@@ -131,22 +210,43 @@
 //! RUST_TEST_THREADS=1 cargo test
 //! ```
 
+// QldbError wraps the rusoto errors as they are, which makes it big, but
+// boxing them would break the public API.
+#![allow(clippy::result_large_err)]
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
 mod client;
+mod client_builder;
 mod cursor;
 mod document;
 mod document_collection;
+mod instrument;
+mod ion_deserializer;
+mod proxy;
 mod query_builder;
 mod session_pool;
 mod transaction;
 mod types;
 
 pub use client::QldbClient;
+pub use client_builder::QldbClientBuilder;
 pub use cursor::Cursor;
 pub use document::Document;
 pub use document_collection::DocumentCollection;
 pub use ion_binary_rs as ion;
 pub use query_builder::QueryBuilder;
 pub use rusoto_core::Region;
+#[cfg(feature = "internal_pool_with_spawner")]
+pub use session_pool::SpawnerSessionPool;
+#[cfg(feature = "internal_pool_with_thread")]
+pub use session_pool::ThreadedSessionPool;
+pub use session_pool::{
+    Backoff, Clock, CommandMetrics, DefaultBackoff, DefaultRetryClassifier, DefaultSessionValidator, Jitter,
+    ManualClock, NoopSessionObserver, PolynomialBackoff, PoolEvent, PoolStats, PooledSession, Priority,
+    RetryClassifier, ReusePolicy, Session, SessionObserver, SessionPool, SessionValidator, SpawnerFnMonoMultithread,
+    SpawnerFnMonothread, SystemClock,
+};
 pub use transaction::Transaction;
 pub use types::{QldbError, QldbResult};
 pub use types::{QldbExtractError, QldbExtractResult};
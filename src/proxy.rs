@@ -0,0 +1,188 @@
+//! Connects to QLDB through an HTTP proxy, opening a tunnel with `CONNECT`
+//! so the TLS connection still goes end to end to AWS.
+
+use crate::{QldbError, QldbResult};
+use base64::Engine;
+use hyper::client::HttpConnector;
+use hyper::Uri;
+use percent_encoding::percent_decode_str;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tower_service::Service;
+
+/// A proxy answer longer than this is most likely not a proxy.
+const MAX_PROXY_RESPONSE_LENGTH: usize = 8 * 1024;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Clone)]
+pub(crate) struct Proxy {
+    uri: Uri,
+    // The `Proxy-Authorization` header, when the URL has credentials
+    authorization: Option<String>,
+    // The hosts reached without the proxy, as found in `NO_PROXY`
+    no_proxy: Vec<String>,
+}
+
+impl Proxy {
+    /// Parses an `http://[user:password@]host:port` URL, as found in
+    /// `HTTPS_PROXY`. The user and the password are percent-decoded, as
+    /// they are in a URL. TLS to the proxy itself is not supported.
+    pub(crate) fn parse(url: &str) -> QldbResult<Proxy> {
+        let invalid = || QldbError::InvalidProxy(url.to_string());
+
+        let uri: Uri = url.parse().map_err(|_| invalid())?;
+
+        if uri.scheme_str() != Some("http") {
+            return Err(invalid());
+        }
+
+        let authority = uri.authority().ok_or_else(invalid)?;
+        let host = authority.host();
+        let port = authority.port_u16().unwrap_or(80);
+
+        let authorization = match authority.as_str().rsplit_once('@') {
+            Some((credentials, _)) => {
+                let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                let decode = |part: &str| {
+                    percent_decode_str(part)
+                        .decode_utf8()
+                        .map(|part| part.into_owned())
+                        .map_err(|_| invalid())
+                };
+                let credentials = format!("{}:{}", decode(user)?, decode(password)?);
+
+                Some(format!(
+                    "Basic {}",
+                    base64::engine::general_purpose::STANDARD.encode(credentials)
+                ))
+            }
+            None => None,
+        };
+
+        let uri = Uri::builder()
+            .scheme("http")
+            .authority(format!("{}:{}", host, port))
+            .path_and_query("/")
+            .build()
+            .map_err(|_| invalid())?;
+
+        Ok(Proxy {
+            uri,
+            authorization,
+            no_proxy: Vec::new(),
+        })
+    }
+
+    /// Skips the proxy for the hosts in a `NO_PROXY` list: comma separated
+    /// host names, matching their subdomains too, or `*` for every host.
+    /// Ports are ignored and IP ranges are not supported.
+    pub(crate) fn bypassing(mut self, no_proxy: &str) -> Proxy {
+        self.no_proxy = no_proxy
+            .split(',')
+            .map(|host| {
+                let host = host.trim().trim_start_matches("*.").trim_start_matches('.');
+                // Only the host matters, but an IPv6 address is full of colons
+                let host = match host.rsplit_once(':') {
+                    Some((name, port)) if !name.contains(':') && port.parse::<u16>().is_ok() => name,
+                    _ => host,
+                };
+                host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase()
+            })
+            .filter(|host| !host.is_empty())
+            .collect();
+        self
+    }
+
+    fn is_bypassed(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+
+        self.no_proxy.iter().any(|pattern| {
+            pattern == "*"
+                || host == *pattern
+                || (host.ends_with(pattern.as_str()) && host[..host.len() - pattern.len()].ends_with('.'))
+        })
+    }
+}
+
+/// Same as `HttpConnector`, but going through the proxy when there is one.
+#[derive(Clone)]
+pub(crate) struct ProxyConnector {
+    http_connector: HttpConnector,
+    proxy: Option<Proxy>,
+}
+
+impl ProxyConnector {
+    pub(crate) fn new(http_connector: HttpConnector, proxy: Option<Proxy>) -> ProxyConnector {
+        ProxyConnector { http_connector, proxy }
+    }
+}
+
+impl Service<Uri> for ProxyConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.http_connector.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, target: Uri) -> Self::Future {
+        let mut http_connector = self.http_connector.clone();
+        let proxy = self.proxy.clone();
+
+        Box::pin(async move {
+            match proxy {
+                Some(proxy) if !target.host().is_some_and(|host| proxy.is_bypassed(host)) => {
+                    let mut stream = http_connector.call(proxy.uri.clone()).await?;
+                    open_tunnel(&mut stream, &proxy, &target).await?;
+                    Ok(stream)
+                }
+                _ => Ok(http_connector.call(target).await?),
+            }
+        })
+    }
+}
+
+async fn open_tunnel(stream: &mut TcpStream, proxy: &Proxy, target: &Uri) -> Result<(), BoxError> {
+    let host = target.host().ok_or("The QLDB endpoint has no host")?;
+    let port = target.port_u16().unwrap_or(443);
+
+    let mut request = format!(
+        "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+        host = host,
+        port = port
+    );
+    if let Some(authorization) = &proxy.authorization {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+    }
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes()).await?;
+
+    // The server doesn't say anything until the TLS handshake starts, so
+    // everything until the end of the headers comes from the proxy
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_PROXY_RESPONSE_LENGTH {
+            return Err("The proxy answer to CONNECT is too long".into());
+        }
+
+        let mut byte = [0u8; 1];
+        if stream.read(&mut byte).await? == 0 {
+            return Err("The proxy closed the connection before opening the tunnel".into());
+        }
+        response.push(byte[0]);
+    }
+
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+
+    match status_line.split_whitespace().nth(1) {
+        Some("200") => Ok(()),
+        _ => Err(format!("The proxy refused to open the tunnel: {}", status_line).into()),
+    }
+}
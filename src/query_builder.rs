@@ -1,26 +1,33 @@
+use crate::instrument::traced_command;
+use crate::session_pool::DynQldbSession;
 use crate::{Cursor, DocumentCollection, QldbError, QldbResult, Transaction};
+use async_compat::CompatExt;
+use async_io::Timer;
+use futures::future::{select, Either};
 use ion_binary_rs::{IonEncoder, IonParser, IonValue};
 use rusoto_qldb_session::{
-    ExecuteStatementRequest, FetchPageRequest, QldbSession, QldbSessionClient, SendCommandRequest, ValueHolder,
+    ExecuteStatementRequest, FetchPageRequest, SendCommandRequest, SendCommandResult, ValueHolder,
 };
 use std::fmt::Debug;
 use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
 use std::sync::Arc;
+use std::time::Duration;
 
 /// Represents the query being built. It allows to add parameters
 /// and to execute the query.
 pub struct QueryBuilder {
     tx: Transaction,
-    client: Arc<QldbSessionClient>,
+    client: Arc<DynQldbSession>,
     statement: Arc<String>,
     params: Vec<IonValue>,
     auto_rollback: bool,
     is_executed: Arc<AtomicBool>,
+    timeout: Option<Duration>,
 }
 
 impl QueryBuilder {
     pub(crate) fn new(
-        client: Arc<QldbSessionClient>,
+        client: Arc<DynQldbSession>,
         tx: Transaction,
         statement: &str,
         auto_rollback: bool,
@@ -32,6 +39,7 @@ impl QueryBuilder {
             params: vec![],
             auto_rollback,
             is_executed: Arc::new(AtomicBool::from(false)),
+            timeout: None,
         }
     }
 
@@ -45,6 +53,28 @@ impl QueryBuilder {
         self
     }
 
+    /// Same as `param` but it adds all the given params at once, in
+    /// the same order as the `?` they refer to.
+    pub fn params<P: Into<IonValue> + Clone>(mut self, params: &[P]) -> Self {
+        self.params.extend(params.iter().cloned().map(Into::into));
+        self
+    }
+
+    /// Limits how long QLDB can take to answer each request of this query,
+    /// i.e. the statement and each of the pages. When it takes longer, the
+    /// query fails with `QldbError::StatementTimeout` and the transaction is
+    /// cancelled, so it cannot be used anymore. Its session is closed
+    /// instead of being given back to the pool, as it may be still busy.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Same as `execute` but with the given timeout. See `timeout`.
+    pub async fn execute_with_timeout(self, timeout: Duration) -> QldbResult<DocumentCollection> {
+        self.timeout(timeout).execute().await
+    }
+
     /// Executes the query in QLDBwith the parameter provided by
     /// the `param` method. It will return a Vector of Ion Values,
     /// one for each document returned.
@@ -69,12 +99,10 @@ impl QueryBuilder {
 
     pub(crate) async fn execute_get_page(&mut self, page_token: &str) -> QldbResult<(Vec<IonValue>, Option<String>)> {
         let result = self
-            .client
-            .send_command(create_next_page_command(
-                self.tx.session.get_session_id(),
-                &self.tx.transaction_id,
-                page_token,
-            ))
+            .send_command(
+                "fetch_page",
+                create_next_page_command(self.tx.session.get_session_id(), &self.tx.transaction_id, page_token),
+            )
             .await?;
 
         let (values, next_page_token) = result
@@ -102,23 +130,39 @@ impl QueryBuilder {
             return Err(QldbError::QueryAlreadyExecuted);
         }
 
-        // TODO: hash_query may be an expesive operation, maybe
-        // we want to move to a task and execute it in parallel
-        // with the waiting of the send_command.
-        self.tx.hash_query(&self.statement, &self.params).await;
+        // QLDB error for this case doesn't tell much about what is wrong
+        let expected = count_placeholders(&self.statement);
+
+        if expected != self.params.len() {
+            return Err(QldbError::WrongParameterCount {
+                expected,
+                provided: self.params.len(),
+            });
+        }
+
+        // The hash is only needed for the commit digest, and auto
+        // rollback transactions are never committed.
+        if !self.auto_rollback {
+            // TODO: hash_query may be an expesive operation, maybe
+            // we want to move to a task and execute it in parallel
+            // with the waiting of the send_command.
+            self.tx.hash_query(&self.statement, &self.params).await;
+        }
 
         let params = std::mem::take(&mut self.params);
 
         self.is_executed.store(true, Relaxed);
 
         let result = self
-            .client
-            .send_command(create_send_command(
-                self.tx.session.get_session_id(),
-                &self.tx.transaction_id,
-                &self.statement,
-                params,
-            ))
+            .send_command(
+                "execute_statement",
+                create_send_command(
+                    self.tx.session.get_session_id(),
+                    &self.tx.transaction_id,
+                    &self.statement,
+                    params,
+                ),
+            )
             .await?;
 
         let (values, next_page_token) = result
@@ -137,6 +181,33 @@ impl QueryBuilder {
         Ok((values, next_page_token))
     }
 
+    async fn send_command(&self, name: &'static str, command: SendCommandRequest) -> QldbResult<SendCommandResult> {
+        let session_id = Some(self.tx.session.get_session_id());
+
+        let sending = traced_command(
+            name,
+            session_id,
+            self.tx.attempt,
+            self.tx.session.observer(),
+            self.client.send_command(command).compat(),
+        );
+
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Ok(sending.await?),
+        };
+
+        futures::pin_mut!(sending);
+
+        match select(sending, Timer::after(timeout)).await {
+            Either::Left((result, _)) => Ok(result?),
+            Either::Right(_) => {
+                self.tx.abandon().await;
+                Err(QldbError::StatementTimeout)
+            }
+        }
+    }
+
     /// Creates a cursor for this query, allowing to load values
     /// page by page. Each page in QLDB contains 200 documents.
     pub fn get_cursor(self) -> QldbResult<Cursor> {
@@ -178,10 +249,56 @@ impl Debug for QueryBuilder {
             .field("statement", &self.statement)
             .field("params", &self.params)
             .field("auto_rollback", &self.auto_rollback)
+            .field("timeout", &self.timeout)
             .finish()
     }
 }
 
+/// Counts the `?` in the statement, skipping the ones inside strings,
+/// quoted identifiers, Ion literals and comments.
+fn count_placeholders(statement: &str) -> usize {
+    let mut count = 0;
+    let mut chars = statement.chars().peekable();
+
+    while let Some(char) = chars.next() {
+        match char {
+            '?' => count += 1,
+            // Escaped quotes are doubled, so skipping until the next
+            // quote and starting again handles them too
+            '\'' | '"' | '`' => {
+                for next in chars.by_ref() {
+                    if next == char {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for next in chars.by_ref() {
+                    if next == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+
+                let mut previous = ' ';
+
+                for next in chars.by_ref() {
+                    if previous == '*' && next == '/' {
+                        break;
+                    }
+
+                    previous = next;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    count
+}
+
 fn valueholders_to_ionvalues(values: Vec<ValueHolder>) -> QldbResult<Vec<IonValue>> {
     let mut decoded_values = vec![];
 
@@ -1,43 +1,74 @@
-use crate::session_pool::agnostic_async_pool_shared::{create_session, provide_session, qldb_close_session};
-use crate::session_pool::{Session, SpawnerFnMonoMultithread};
+use crate::session_pool::agnostic_async_pool_shared::{
+    create_session, creation_slot, end_session, next_pool_command, notify_session_closed, provide_close_result,
+    provide_drained_count, provide_idle_session_ids, provide_session, provide_stats, qldb_keep_alive_session,
+    queue_permit, queue_waiter, remove_canceled_waiters, request_session_creation, session_count_at_server_limit,
+    take_expired_sessions, wait_for_no_sessions,
+};
+use crate::session_pool::DynQldbSession;
+use crate::session_pool::{
+    PoolCommand, PoolConfig, PoolEvent, PoolStats, Session, SessionSender, SpawnerFnMonoMultithread,
+};
 use async_channel::Receiver;
 use async_channel::Sender;
 use async_io::Timer;
-use log::error;
-use rusoto_qldb_session::QldbSessionClient;
+use futures::future::{join, join_all, select};
+use log::{error, warn};
 use std::collections::VecDeque;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::{
-    atomic::{AtomicBool, AtomicU16},
+    atomic::{AtomicBool, AtomicU16, AtomicU64},
     Arc, Mutex,
 };
+use std::task::{Context, Poll};
 use std::time::Duration;
 
 #[allow(clippy::too_many_arguments)]
 pub fn receiver_task(
     spawner: SpawnerFnMonoMultithread,
-    max_sessions: u16,
-    ledger_name: &str,
+    config: &PoolConfig,
     sessions: &Arc<Mutex<VecDeque<Session>>>,
+    waiters: &Arc<Mutex<VecDeque<SessionSender>>>,
     session_count: &Arc<AtomicU16>,
-    qldb_client: &Arc<QldbSessionClient>,
+    qldb_client: &Arc<DynQldbSession>,
     is_closed: &Arc<AtomicBool>,
-    requesting_receiver: Receiver<Sender<Session>>,
-    requesting_sender: Sender<Sender<Session>>,
+    requesting_receiver: Receiver<SessionSender>,
+    creating_sender: Sender<()>,
 ) {
+    let config = config.clone();
     let is_closed = is_closed.clone();
     let qldb_client = qldb_client.clone();
     let sessions = sessions.clone();
+    let waiters = waiters.clone();
     let session_count = session_count.clone();
-    let ledger_name = ledger_name.to_owned();
 
     spawner.clone()(Box::pin(async move {
-        while let Ok(sender) = requesting_receiver.recv().await {
+        loop {
+            // Taken before the next request, so the ones past the capacity are
+            // left in the channel, making the callers wait
+            let queue_permit = queue_permit(&config, |cx| remove_canceled(&waiters, cx)).await;
+
+            let Ok(sender) = requesting_receiver.recv().await else {
+                break;
+            };
+
             if is_closed.load(Relaxed) {
                 break;
             }
 
             loop {
+                // The waiters are locked until the request is queued, as the
+                // returning sessions are stored while locking them too. If not,
+                // a session could be stored as idle while we queue the waiter.
+                let mut waiters_lock = match waiters.lock() {
+                    Ok(waiters) => waiters,
+                    Err(err) => {
+                        // Means that something went really wrong
+                        is_closed.store(true, Relaxed);
+                        error!("QLDB driver internal fatal error. Cannot get lock at waiters when requesting a session: {:?}", err);
+                        break;
+                    }
+                };
+
                 let (session, pooled_sessions_count) = match sessions.lock() {
                     Ok(mut sessions) => (sessions.pop_back(), sessions.len()),
                     Err(err) => {
@@ -49,21 +80,37 @@ pub fn receiver_task(
                 };
 
                 if let Some(session) = session {
+                    drop(waiters_lock);
+
                     if session.is_valid() {
-                        provide_session(&sender, session);
+                        provide_session(sender, session);
+                        config.served.from_pool.fetch_add(1, Relaxed);
+
+                        if pooled_sessions_count < config.min_idle.into() {
+                            request_session_creation(&creating_sender);
+                        }
+
                         break;
                     } else {
-                        close_session(spawner.clone(), &qldb_client, session, &session_count);
+                        retire_session(
+                            spawner.clone(),
+                            &qldb_client,
+                            &config,
+                            session,
+                            &session_count,
+                            &creating_sender,
+                        );
                         // Continue so we try next available session
                         continue;
                     }
                 } else {
-                    if pooled_sessions_count < max_sessions.into() {
-                        refill_session(&qldb_client.clone(), &ledger_name, &sessions).await;
-                        continue;
-                    } else {
-                        requeue_session_request(&requesting_sender, sender);
-                    }
+                    // Forget about the ones that stopped waiting
+                    waiters_lock.retain(|waiter| !waiter.is_canceled());
+                    queue_waiter(&mut waiters_lock, sender.with_queue_permit(queue_permit));
+                    config.events.emit(PoolEvent::RequestQueued);
+                    drop(waiters_lock);
+
+                    request_session_creation(&creating_sender);
                     break;
                 }
             }
@@ -71,95 +118,783 @@ pub fn receiver_task(
     }));
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn returning_task(
     spawner: SpawnerFnMonoMultithread,
+    config: &PoolConfig,
     sessions: &Arc<Mutex<VecDeque<Session>>>,
+    waiters: &Arc<Mutex<VecDeque<SessionSender>>>,
     session_count: &Arc<AtomicU16>,
-    qldb_client: &Arc<QldbSessionClient>,
+    server_limit: &Arc<AtomicU16>,
+    qldb_client: &Arc<DynQldbSession>,
     is_closed: &Arc<AtomicBool>,
     returning_receiver: Receiver<Session>,
+    creating_sender: Sender<()>,
 ) {
+    let config = config.clone();
     let is_closed = is_closed.clone();
     let qldb_client = qldb_client.clone();
     let sessions = sessions.clone();
+    let waiters = waiters.clone();
     let session_count = session_count.clone();
+    let server_limit = server_limit.clone();
 
     spawner.clone()(Box::pin(async move {
         while let Ok(session) = returning_receiver.recv().await {
+            // Queuing it again would hand the same session to two transactions
+            if is_idle(&sessions, &session) {
+                warn!(
+                    "The QLDB session {} was given back to the pool twice, ignoring the second time",
+                    session.get_session_id()
+                );
+                continue;
+            }
+
+            session.record_use();
+            config.events.emit(PoolEvent::SessionReturned {
+                session_id: session.get_session_id().to_string(),
+            });
+
+            // QLDB may have room again, and the waiters will take this one anyway
+            server_limit.store(u16::MAX, Relaxed);
+
+            // Once closed, the sessions still in use are closed as they come back.
+            // Past `max_idle` nobody needs them either, unless someone is waiting.
+            if is_closed.load(Relaxed)
+                || !session.is_valid()
+                || (is_idle_full(&sessions, &config) && waiters_count(&waiters) == 0)
+            {
+                close_session(
+                    spawner.clone(),
+                    &qldb_client,
+                    &config,
+                    session,
+                    &session_count,
+                    &creating_sender,
+                );
+                continue;
+            }
+
+            if let Err(session) = deliver_session(
+                &sessions,
+                &waiters,
+                &is_closed,
+                session,
+                config.reuse_policy.returns_to_front(),
+                &config.served.from_pool,
+            ) {
+                close_session(
+                    spawner.clone(),
+                    &qldb_client,
+                    &config,
+                    session,
+                    &session_count,
+                    &creating_sender,
+                );
+                break;
+            }
+        }
+    }));
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn creator_task(
+    spawner: SpawnerFnMonoMultithread,
+    max_sessions: &Arc<AtomicU16>,
+    ledger_name: &str,
+    pool_id: u64,
+    config: &PoolConfig,
+    sessions: &Arc<Mutex<VecDeque<Session>>>,
+    waiters: &Arc<Mutex<VecDeque<SessionSender>>>,
+    session_count: &Arc<AtomicU16>,
+    server_limit: &Arc<AtomicU16>,
+    overflow_created: &Arc<AtomicU64>,
+    qldb_client: &Arc<DynQldbSession>,
+    is_closed: &Arc<AtomicBool>,
+    creating_receiver: Receiver<()>,
+    creating_sender: Sender<()>,
+) {
+    let config = config.clone();
+    let is_closed = is_closed.clone();
+    let qldb_client = qldb_client.clone();
+    let sessions = sessions.clone();
+    let waiters = waiters.clone();
+    let session_count = session_count.clone();
+    let server_limit = server_limit.clone();
+    let overflow_created = overflow_created.clone();
+    let ledger_name = ledger_name.to_owned();
+    let max_sessions = max_sessions.clone();
+
+    // Sessions being created right now
+    let in_flight = Arc::new(AtomicU16::new(0));
+
+    spawner.clone()(Box::pin(async move {
+        while let Ok(()) = creating_receiver.recv().await {
+            if is_closed.load(Relaxed) {
+                break;
+            }
+
+            // The requests queued meanwhile all mean "more sessions may be
+            // needed", which the demand checked below already answers
+            while creating_receiver.try_recv().is_ok() {}
+
+            // As many creations as the waiters and `min_idle` need are
+            // started at once, so a burst of requests doesn't wait for
+            // the sessions to be created one after the other. Every
+            // creation asks for a new one once finished, so the requests
+            // ignored here will be checked again later.
+            loop {
+                if in_flight.load(Relaxed) >= config.creation_concurrency
+                    || !is_session_needed(&sessions, &waiters, &config, in_flight.load(Relaxed))
+                {
+                    break;
+                }
+
+                let is_overflow = match creation_slot(
+                    &config,
+                    max_sessions.load(Relaxed),
+                    server_limit.load(Relaxed),
+                    session_count.load(Relaxed),
+                    waiters_count(&waiters) > in_flight.load(Relaxed).into(),
+                ) {
+                    Some(is_overflow) => is_overflow,
+                    None => break,
+                };
+
+                if is_overflow {
+                    overflow_created.fetch_add(1, Relaxed);
+                }
+
+                session_count.fetch_add(1, Relaxed);
+                in_flight.fetch_add(1, Relaxed);
+
+                let spawner_create = spawner.clone();
+                let config = config.clone();
+                let is_closed = is_closed.clone();
+                let qldb_client = qldb_client.clone();
+                let sessions = sessions.clone();
+                let waiters = waiters.clone();
+                let session_count = session_count.clone();
+                let server_limit = server_limit.clone();
+                let in_flight = in_flight.clone();
+                let ledger_name = ledger_name.clone();
+                let creating_sender = creating_sender.clone();
+
+                spawner(Box::pin(async move {
+                    match create_session(&qldb_client, &ledger_name, pool_id, &config).await {
+                        Ok(session) => {
+                            let delivered = if is_closed.load(Relaxed) {
+                                Err(session)
+                            } else if is_overflow {
+                                // Only for the ones waiting, it is never kept idle
+                                deliver_to_waiter(&waiters, session.single_use(), &config.served.from_new)
+                            } else {
+                                deliver_session(
+                                    &sessions,
+                                    &waiters,
+                                    &is_closed,
+                                    session,
+                                    false,
+                                    &config.served.from_new,
+                                )
+                            };
+
+                            match delivered {
+                                // Only counted once it can be used. An overflow one counts
+                                // too, the pool is at `max_sessions`, as far as a warm goes.
+                                Ok(()) => config.warming.session_created(),
+                                Err(session) => close_session(
+                                    spawner_create,
+                                    &qldb_client,
+                                    &config,
+                                    session,
+                                    &session_count,
+                                    &creating_sender,
+                                ),
+                            }
+                        }
+                        Err(err) => {
+                            config.events.emit(PoolEvent::CreationFailed {
+                                error: format!("{:#}", err.report()),
+                            });
+
+                            decrease_session_count(&session_count);
+                            notify_session_closed(&config);
+
+                            // Serve from the sessions we have instead of asking QLDB again and again
+                            if let Some(limit) = session_count_at_server_limit(&err, session_count.load(Relaxed)) {
+                                server_limit.store(limit, Relaxed);
+                            }
+
+                            config.clock.sleep(config.creation_retry_delay).await;
+                        }
+                    }
+
+                    decrease_session_count(&in_flight);
+
+                    request_session_creation(&creating_sender);
+                }));
+            }
+        }
+    }));
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn command_task(
+    spawner: SpawnerFnMonoMultithread,
+    config: &PoolConfig,
+    sessions: &Arc<Mutex<VecDeque<Session>>>,
+    waiters: &Arc<Mutex<VecDeque<SessionSender>>>,
+    max_sessions: &Arc<AtomicU16>,
+    session_count: &Arc<AtomicU16>,
+    overflow_created: &Arc<AtomicU64>,
+    qldb_client: &Arc<DynQldbSession>,
+    is_closed: &Arc<AtomicBool>,
+    command_receiver: Receiver<PoolCommand>,
+    creating_sender: Sender<()>,
+    closing_sender: Sender<()>,
+) {
+    let config = config.clone();
+    let is_closed = is_closed.clone();
+    let qldb_client = qldb_client.clone();
+    let sessions = sessions.clone();
+    let waiters = waiters.clone();
+    let session_count = session_count.clone();
+    let max_sessions = max_sessions.clone();
+    let overflow_created = overflow_created.clone();
+
+    spawner.clone()(Box::pin(async move {
+        // Set once the pool is closed, so later calls to close, from any
+        // clone, get the same result instead of an error
+        let mut close_result: Option<u16> = None;
+
+        let mut shutdown_signal = config.shutdown_signal.clone();
+
+        while let Some(command) = next_pool_command(&command_receiver, &mut shutdown_signal, &is_closed).await {
+            if let Some(still_open) = close_result {
+                if let PoolCommand::Close(sender) = command {
+                    provide_close_result(&sender, still_open);
+                }
+
+                continue;
+            }
+
+            if is_closed.load(Relaxed) {
+                break;
+            }
+
+            match command {
+                PoolCommand::Stats(sender) => {
+                    let stats = PoolStats {
+                        active: session_count.load(Relaxed),
+                        idle: sessions.lock().map(|sessions| sessions.len()).unwrap_or(0),
+                        waiters: waiters
+                            .lock()
+                            .map(|waiters| waiters.iter().filter(|waiter| !waiter.is_canceled()).count())
+                            .unwrap_or(0),
+                        max_sessions: max_sessions.load(Relaxed),
+                        overflow_sessions_created: overflow_created.load(Relaxed),
+                        served_from_pool: config.served.from_pool.load(Relaxed),
+                        served_from_new: config.served.from_new.load(Relaxed),
+                    };
+
+                    provide_stats(&sender, stats);
+                }
+                PoolCommand::DrainIdle(sender) => {
+                    let idle_sessions: Vec<Session> = sessions
+                        .lock()
+                        .map(|mut sessions| sessions.drain(..).collect())
+                        .unwrap_or_default();
+                    let drained = idle_sessions.len();
+
+                    // Closing them asks for new ones, which are only created
+                    // if someone is waiting or to keep the minimum idle
+                    for session in idle_sessions {
+                        close_session(
+                            spawner.clone(),
+                            &qldb_client,
+                            &config,
+                            session,
+                            &session_count,
+                            &creating_sender,
+                        );
+                    }
+
+                    provide_drained_count(&sender, drained);
+                }
+                PoolCommand::IdleSessionIds(sender) => {
+                    let session_ids = sessions
+                        .lock()
+                        .map(|sessions| {
+                            sessions
+                                .iter()
+                                .map(|session| session.get_session_id().to_string())
+                                .collect()
+                        })
+                        .unwrap_or_default();
+
+                    provide_idle_session_ids(&sender, session_ids);
+                }
+                PoolCommand::SetMax(new_max) => {
+                    // Lowering it doesn't close anything, the extra sessions
+                    // will be closed as they expire
+                    let old_max = max_sessions.swap(new_max, Relaxed);
+
+                    // The new slots may be used by the ones waiting
+                    for _ in old_max..new_max {
+                        request_session_creation(&creating_sender);
+                    }
+                }
+                PoolCommand::Warm(target, sender) => {
+                    // The ones in use or being created count as open
+                    config
+                        .warming
+                        .request(target.saturating_sub(session_count.load(Relaxed)));
+                    request_session_creation(&creating_sender);
+
+                    let _ = sender.try_send(());
+                }
+                command @ (PoolCommand::Close(_) | PoolCommand::Shutdown) => {
+                    is_closed.store(true, Relaxed);
+                    // Wakes up the ones waiting in `closed`
+                    closing_sender.close();
+                    // and the ones waiting in `warm`, nothing else is created
+                    config.warming.request(0);
+
+                    let still_open = drain_sessions(&config, &sessions, &waiters, &session_count, &qldb_client).await;
+
+                    if let PoolCommand::Close(sender) = command {
+                        provide_close_result(&sender, still_open);
+                    }
+
+                    close_result = Some(still_open);
+                }
+                PoolCommand::TryRequest(sender) => {
+                    let session = loop {
+                        match pop_idle_session(&sessions) {
+                            Some(session) if session.is_valid() => break Some(session),
+                            Some(session) => retire_session(
+                                spawner.clone(),
+                                &qldb_client,
+                                &config,
+                                session,
+                                &session_count,
+                                &creating_sender,
+                            ),
+                            None => break None,
+                        }
+                    };
+
+                    let session_id = session.as_ref().map(|session| session.get_session_id().to_string());
+
+                    // If the requester is gone the session goes back to the pool
+                    match sender.try_send(session) {
+                        Ok(()) => {
+                            if let Some(session_id) = session_id {
+                                config.served.from_pool.fetch_add(1, Relaxed);
+                                config.events.emit(PoolEvent::SessionBorrowed { session_id });
+                            }
+                        }
+                        Err(err) => {
+                            if let Some(session) = err.into_inner() {
+                                if let Err(session) = deliver_session(
+                                    &sessions,
+                                    &waiters,
+                                    &is_closed,
+                                    session,
+                                    config.reuse_policy.returns_to_front(),
+                                    &config.served.from_pool,
+                                ) {
+                                    close_session(
+                                        spawner.clone(),
+                                        &qldb_client,
+                                        &config,
+                                        session,
+                                        &session_count,
+                                        &creating_sender,
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }));
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn keep_alive_task(
+    spawner: SpawnerFnMonoMultithread,
+    interval: Duration,
+    config: &PoolConfig,
+    sessions: &Arc<Mutex<VecDeque<Session>>>,
+    session_count: &Arc<AtomicU16>,
+    qldb_client: &Arc<DynQldbSession>,
+    is_closed: &Arc<AtomicBool>,
+    creating_sender: Sender<()>,
+) {
+    let config = config.clone();
+    let is_closed = is_closed.clone();
+    let qldb_client = qldb_client.clone();
+    let sessions = sessions.clone();
+    let session_count = session_count.clone();
+
+    spawner.clone()(Box::pin(async move {
+        loop {
+            Timer::after(interval).await;
+
             if is_closed.load(Relaxed) {
                 break;
             }
 
-            if !session.is_valid() {
-                close_session(spawner.clone(), &qldb_client, session, &session_count);
+            let idle_sessions: Vec<Session> = match sessions.lock() {
+                Ok(sessions) => sessions.iter().cloned().collect(),
+                Err(err) => {
+                    // Means that something went really wrong
+                    is_closed.store(true, Relaxed);
+                    error!(
+                        "QLDB driver internal fatal error. Cannot get lock at sessions when keeping them alive: {:?}",
+                        err
+                    );
+                    break;
+                }
+            };
+
+            for session in idle_sessions {
+                let is_alive = qldb_keep_alive_session(&qldb_client, &session).await.is_ok();
+
+                let replacement = if is_alive { Some(session.refreshed()) } else { None };
+
+                // If it isn't idle anymore somebody is using it, so it is not our business
+                if replace_idle_session(&sessions, &session, replacement) && !is_alive {
+                    close_session(
+                        spawner.clone(),
+                        &qldb_client,
+                        &config,
+                        session,
+                        &session_count,
+                        &creating_sender,
+                    );
+                }
+            }
+        }
+    }));
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn sweep_task(
+    spawner: SpawnerFnMonoMultithread,
+    interval: Duration,
+    config: &PoolConfig,
+    sessions: &Arc<Mutex<VecDeque<Session>>>,
+    session_count: &Arc<AtomicU16>,
+    qldb_client: &Arc<DynQldbSession>,
+    is_closed: &Arc<AtomicBool>,
+    creating_sender: Sender<()>,
+) {
+    let config = config.clone();
+    let is_closed = is_closed.clone();
+    let qldb_client = qldb_client.clone();
+    let sessions = sessions.clone();
+    let session_count = session_count.clone();
+
+    spawner.clone()(Box::pin(async move {
+        loop {
+            Timer::after(interval).await;
+
+            if is_closed.load(Relaxed) {
                 break;
             }
 
-            match sessions.lock() {
-                Ok(mut sessions) => sessions.push_front(session),
+            let expired_sessions: Vec<Session> = match sessions.lock() {
+                Ok(mut sessions) => take_expired_sessions(&mut sessions),
                 Err(err) => {
                     // Means that something went really wrong
                     is_closed.store(true, Relaxed);
                     error!(
-                        "QLDB driver internal fatal error. Cannot get lock at sessions when returning a session: {:?}",
+                        "QLDB driver internal fatal error. Cannot get lock at sessions when sweeping them: {:?}",
                         err
                     );
-                    close_session(spawner.clone(), &qldb_client, session, &session_count);
                     break;
                 }
             };
+
+            // Closing the sessions will trigger the creation of new
+            // ones if we are under the minimum of idle sessions
+            for session in expired_sessions {
+                close_session(
+                    spawner.clone(),
+                    &qldb_client,
+                    &config,
+                    session,
+                    &session_count,
+                    &creating_sender,
+                );
+            }
         }
     }));
 }
 
+/// Closes all the idle sessions and waits, for as long as the configured
+/// grace period, for the sessions in use to be returned and closed. It
+/// returns how many sessions are still open.
+async fn drain_sessions(
+    config: &PoolConfig,
+    sessions: &Arc<Mutex<VecDeque<Session>>>,
+    waiters: &Arc<Mutex<VecDeque<SessionSender>>>,
+    session_count: &Arc<AtomicU16>,
+    qldb_client: &Arc<DynQldbSession>,
+) -> u16 {
+    // Without their sender the waiters will get an error
+    if let Ok(mut waiters) = waiters.lock() {
+        waiters.clear();
+    }
+
+    let idle_sessions: Vec<Session> = match sessions.lock() {
+        Ok(mut sessions) => sessions.drain(..).collect(),
+        Err(_) => vec![],
+    };
+
+    // Closed here instead of on their own tasks, so the grace period bounds
+    // them too and, as they are polled first, a clock that doesn't really
+    // wait (e.g. `ManualClock`) doesn't skip them. The ones not closed in
+    // time are left for QLDB to expire.
+    let closing_idle = join_all(idle_sessions.iter().map(|session| async move {
+        end_session(qldb_client, config, session).await;
+
+        decrease_session_count(session_count);
+        notify_session_closed(config);
+    }));
+
+    let closing = join(
+        closing_idle,
+        wait_for_no_sessions(config, || session_count.load(Relaxed)),
+    );
+    futures::pin_mut!(closing);
+
+    let _ = select(closing, config.clock.sleep(config.close_grace_period)).await;
+
+    session_count.load(Relaxed)
+}
+
 fn close_session(
     spawner: SpawnerFnMonoMultithread,
-    qldb_client: &Arc<QldbSessionClient>,
+    qldb_client: &Arc<DynQldbSession>,
+    config: &PoolConfig,
     session: Session,
     session_count: &Arc<AtomicU16>,
+    creating_sender: &Sender<()>,
 ) {
+    let config = config.clone();
     let qldb_client = qldb_client.clone();
     let session_count = session_count.clone();
+    let creating_sender = creating_sender.clone();
 
     spawner(Box::pin(async move {
-        let mut tries: u32 = 0;
+        end_session(&qldb_client, &config, &session).await;
 
-        loop {
-            tries = tries.saturating_add(1);
-
-            match qldb_close_session(&qldb_client, &session).await {
-                Ok(_) => break,
-                Err(_) if tries > 10 => break,
-                Err(_) => {
-                    Timer::after(Duration::from_millis(
-                        tries.saturating_mul(tries).saturating_mul(75).into(),
-                    ))
-                    .await;
-                }
-            }
-        }
+        decrease_session_count(&session_count);
+        notify_session_closed(&config);
 
-        session_count.store(session_count.load(Relaxed).saturating_sub(1), Relaxed);
+        // There may be someone waiting for the slot we just freed
+        request_session_creation(&creating_sender);
     }));
 }
 
-fn requeue_session_request(session_requests: &Sender<Sender<Session>>, sender: Sender<Session>) {
-    if let Err(err) = session_requests.try_send(sender) {
-        error!(
-            "QLDB driver internal error. Cannot enqueue session due pool bug: {:?}",
-            err
-        );
+/// Same as `close_session` but the slot of the session is freed right away,
+/// so a replacement can be created while QLDB ends the old one. Used for
+/// the expired sessions found when serving a request, so the requester
+/// doesn't wait for the cleanup.
+fn retire_session(
+    spawner: SpawnerFnMonoMultithread,
+    qldb_client: &Arc<DynQldbSession>,
+    config: &PoolConfig,
+    session: Session,
+    session_count: &Arc<AtomicU16>,
+    creating_sender: &Sender<()>,
+) {
+    decrease_session_count(session_count);
+    notify_session_closed(config);
+
+    request_session_creation(creating_sender);
+
+    let config = config.clone();
+    let qldb_client = qldb_client.clone();
+
+    spawner(Box::pin(async move {
+        end_session(&qldb_client, &config, &session).await;
+    }));
+}
+
+/// Replaces the idle session with the same id with the given one, or removes it
+/// if there is no replacement. Returns false if the session is not idle anymore.
+fn replace_idle_session(
+    sessions: &Arc<Mutex<VecDeque<Session>>>,
+    session: &Session,
+    replacement: Option<Session>,
+) -> bool {
+    let mut sessions = match sessions.lock() {
+        Ok(sessions) => sessions,
+        Err(_) => return false,
+    };
+
+    let position = sessions
+        .iter()
+        .position(|idle_session| idle_session.get_session_id() == session.get_session_id());
+
+    match (position, replacement) {
+        (Some(position), Some(replacement)) => sessions[position] = replacement,
+        (Some(position), None) => {
+            sessions.remove(position);
+        }
+        (None, _) => return false,
     }
+
+    true
 }
 
-async fn refill_session(
-    qldb_client: &Arc<QldbSessionClient>,
-    ledger_name: &str,
+/// Hands the session to the oldest waiter still listening or, if nobody
+/// is waiting, stores it in the pool. It gives the session back as error
+/// when it cannot be stored so the caller can close it. `served` counts
+/// the sessions handed to a waiter.
+fn deliver_session(
     sessions: &Arc<Mutex<VecDeque<Session>>>,
-) {
-    if let Ok(session) = create_session(&qldb_client.clone(), ledger_name).await {
-        if let Ok(mut sessions) = sessions.lock() {
-            sessions.push_back(session);
+    waiters: &Arc<Mutex<VecDeque<SessionSender>>>,
+    is_closed: &Arc<AtomicBool>,
+    mut session: Session,
+    at_front: bool,
+    served: &AtomicU64,
+) -> Result<(), Session> {
+    // The waiters are locked until the session is stored, so a new
+    // request cannot be queued in between without seeing the session.
+    let mut waiters = match waiters.lock() {
+        Ok(waiters) => waiters,
+        Err(err) => {
+            // Means that something went really wrong
+            is_closed.store(true, Relaxed);
+            error!(
+                "QLDB driver internal fatal error. Cannot get lock at waiters when storing a session: {:?}",
+                err
+            );
+            return Err(session);
         }
+    };
+
+    while let Some(waiter) = waiters.pop_front() {
+        // If the waiter is gone we try with the next one
+        match waiter.send(session) {
+            Ok(_) => {
+                served.fetch_add(1, Relaxed);
+                return Ok(());
+            }
+            Err(unsent) => session = unsent,
+        }
+    }
+
+    match sessions.lock() {
+        Ok(mut sessions) => {
+            if at_front {
+                sessions.push_front(session);
+            } else {
+                sessions.push_back(session);
+            }
+            Ok(())
+        }
+        Err(err) => {
+            // Means that something went really wrong
+            is_closed.store(true, Relaxed);
+            error!(
+                "QLDB driver internal fatal error. Cannot get lock at sessions when storing a session: {:?}",
+                err
+            );
+            Err(session)
+        }
+    }
+}
+
+fn pop_idle_session(sessions: &Arc<Mutex<VecDeque<Session>>>) -> Option<Session> {
+    sessions.lock().ok().and_then(|mut sessions| sessions.pop_back())
+}
+
+/// Hands the session to the first request still waiting, giving it back
+/// if there is none. `served` counts it if it was handed.
+fn deliver_to_waiter(
+    waiters: &Arc<Mutex<VecDeque<SessionSender>>>,
+    mut session: Session,
+    served: &AtomicU64,
+) -> Result<(), Session> {
+    let mut waiters = match waiters.lock() {
+        Ok(waiters) => waiters,
+        Err(_) => return Err(session),
+    };
+
+    while let Some(waiter) = waiters.pop_front() {
+        // If the waiter is gone we try with the next one
+        match waiter.send(session) {
+            Ok(_) => {
+                served.fetch_add(1, Relaxed);
+                return Ok(());
+            }
+            Err(unsent) => session = unsent,
+        }
+    }
+
+    Err(session)
+}
+
+/// Whether a session with the same id is already idle in the pool, as
+/// when a clone of the session is given back twice.
+fn is_idle(sessions: &Arc<Mutex<VecDeque<Session>>>, session: &Session) -> bool {
+    sessions
+        .lock()
+        .map(|sessions| {
+            sessions
+                .iter()
+                .any(|idle| idle.get_session_id() == session.get_session_id())
+        })
+        .unwrap_or(false)
+}
+
+/// Whether the pool already keeps `max_idle` idle sessions.
+fn is_idle_full(sessions: &Arc<Mutex<VecDeque<Session>>>, config: &PoolConfig) -> bool {
+    match config.max_idle {
+        Some(max_idle) => sessions.lock().map(|sessions| sessions.len()).unwrap_or(0) >= max_idle.into(),
+        None => false,
     }
 }
+
+fn is_session_needed(
+    sessions: &Arc<Mutex<VecDeque<Session>>>,
+    waiters: &Arc<Mutex<VecDeque<SessionSender>>>,
+    config: &PoolConfig,
+    in_flight: u16,
+) -> bool {
+    let idle_count = sessions.lock().map(|sessions| sessions.len()).unwrap_or(0);
+    let missing_idle = usize::from(config.min_idle).saturating_sub(idle_count);
+
+    // The sessions being created will take care of some of them
+    waiters_count(waiters) + missing_idle + usize::from(config.warming.missing()) > in_flight.into()
+}
+
+fn decrease_session_count(session_count: &Arc<AtomicU16>) {
+    let _ = session_count.fetch_update(Relaxed, Relaxed, |count| Some(count.saturating_sub(1)));
+}
+
+fn waiters_count(waiters: &Arc<Mutex<VecDeque<SessionSender>>>) -> usize {
+    waiters
+        .lock()
+        .map(|mut waiters| {
+            // Forget about the ones that stopped waiting
+            waiters.retain(|waiter| !waiter.is_canceled());
+            waiters.len()
+        })
+        .unwrap_or(0)
+}
+
+fn remove_canceled(waiters: &Arc<Mutex<VecDeque<SessionSender>>>, cx: &mut Context<'_>) -> Poll<()> {
+    waiters
+        .lock()
+        .map(|mut waiters| remove_canceled_waiters(&mut waiters, cx))
+        .unwrap_or(Poll::Pending)
+}
@@ -1,62 +1,548 @@
-use crate::session_pool::{GetSessionError, Session};
-use async_channel::Sender;
+use crate::instrument::{traced_backoff, traced_command};
+use crate::session_pool::{
+    DynQldbSession, GetSessionError, PoolCommand, PoolConfig, PoolEvent, PoolStats, Priority, Session,
+    SessionPoolError, SessionSender, ShutdownSignal, Warming,
+};
+use crate::types::AwsErrorMetadata;
+use async_channel::{Receiver, Sender, TrySendError};
 use async_compat::CompatExt;
 use async_io::Timer;
-use log::error;
+use async_lock::SemaphoreGuardArc;
+use futures::channel::oneshot;
+use futures::future::{poll_fn, select, Either};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{error, warn};
 use rusoto_core::RusotoError;
-use rusoto_qldb_session::{EndSessionRequest, QldbSession, QldbSessionClient, SendCommandRequest, StartSessionRequest};
+use rusoto_qldb_session::{EndSessionRequest, SendCommandError, SendCommandRequest, StartSessionRequest};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
 
-pub(crate) fn provide_session(sender: &Sender<Session>, session: Session) {
+pub(crate) fn provide_session(sender: SessionSender, session: Session) {
+    // The requester is only gone if its future was dropped
+    if let Err(session) = sender.send(session) {
+        error!(
+            "QLDB driver internal error. Cannot return session {} as the requester is gone",
+            session.get_session_id()
+        );
+    }
+}
+
+/// Queues the request behind the ones with the same or a higher priority.
+pub(crate) fn queue_waiter(waiters: &mut VecDeque<SessionSender>, sender: SessionSender) {
+    match sender.priority() {
+        Priority::High => {
+            let position = waiters
+                .iter()
+                .position(|waiter| waiter.priority() != Priority::High)
+                .unwrap_or(waiters.len());
+
+            waiters.insert(position, sender);
+        }
+        Priority::Normal => waiters.push_back(sender),
+    }
+}
+
+/// Waits for the requested session for as long as `timeout`. If the time
+/// passes it returns None, making sure that a session that arrives at the
+/// last moment is returned to the pool instead of being lost.
+pub(crate) async fn receive_session_with_timeout(
+    mut receiver: oneshot::Receiver<Session>,
+    returning_sender: &Sender<Session>,
+    is_closed: &AtomicBool,
+    timeout: Duration,
+) -> eyre::Result<Option<Session>> {
+    match select(&mut receiver, Timer::after(timeout)).await {
+        Either::Left((session, _)) => Ok(Some(session.map_err(|_| pool_stopped_error(is_closed))?)),
+        Either::Right(_) => {
+            // Closing the channel makes the pool skip us when delivering sessions
+            receiver.close();
+
+            if let Ok(Some(session)) = receiver.try_recv() {
+                let _ = returning_sender.try_send(session);
+            }
+
+            Ok(None)
+        }
+    }
+}
+
+/// Queues the request, waiting for as long as `timeout` if the queue is
+/// full. It returns false if there wasn't room in time.
+pub(crate) async fn send_request_with_timeout(
+    requesting_sender: &Sender<SessionSender>,
+    sender: SessionSender,
+    is_closed: &AtomicBool,
+    timeout: Duration,
+) -> eyre::Result<bool> {
+    let sending = requesting_sender.send(sender);
+    futures::pin_mut!(sending);
+
+    match select(sending, Timer::after(timeout)).await {
+        Either::Left((result, _)) => {
+            result.map_err(|_| pool_stopped_error(is_closed))?;
+            Ok(true)
+        }
+        Either::Right(_) => Ok(false),
+    }
+}
+
+/// Waits for the sessions asked for by a warm, for as long as `timeout`.
+/// It returns false if they weren't created in time, forgetting the ones
+/// still missing so the pool doesn't keep creating them.
+pub(crate) async fn wait_for_warming(warming: &Warming, timeout: Duration) -> bool {
+    let waiting = warming.wait();
+    futures::pin_mut!(waiting);
+
+    match select(waiting, Timer::after(timeout)).await {
+        Either::Left(_) => true,
+        Either::Right(_) => {
+            warming.request(0);
+            false
+        }
+    }
+}
+
+/// Runs `n` session requests at once. Once one fails the others are
+/// dropped and the sessions already obtained are given back, so none of
+/// them is kept out of the pool.
+pub(crate) async fn get_many_sessions<F>(
+    n: usize,
+    get: impl Fn() -> F,
+    give_back: impl Fn(Session),
+) -> eyre::Result<Vec<Session>>
+where
+    F: Future<Output = eyre::Result<Session>>,
+{
+    let mut requests: FuturesUnordered<F> = (0..n).map(|_| get()).collect();
+    let mut sessions = Vec::with_capacity(n);
+
+    while let Some(result) = requests.next().await {
+        match result {
+            Ok(session) => sessions.push(session),
+            Err(err) => {
+                sessions.into_iter().for_each(give_back);
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(sessions)
+}
+
+/// Sessions from other pools would be handed to unrelated callers and they
+/// would break the session count of this pool, so they are ignored.
+pub(crate) fn is_foreign_session(session: &Session, pool_id: u64) -> bool {
+    let is_foreign = session.get_pool_id() != pool_id;
+
+    if is_foreign {
+        warn!(
+            "Ignoring session {} as it doesn't belong to this session pool",
+            session.get_session_id()
+        );
+    }
+
+    is_foreign
+}
+
+/// Logs a session given back that the pool couldn't take, as it is still
+/// open in QLDB until it expires but the pool doesn't count it anymore.
+/// A closed pool doesn't want the sessions back, a dead one is an error.
+pub(crate) fn log_dropped_session(err: TrySendError<Session>, is_closed: &AtomicBool, cause: impl FnOnce() -> String) {
+    let reason = if err.is_full() { "Full" } else { "Closed" };
+    let session = err.into_inner();
+
+    if is_closed.load(Relaxed) {
+        warn!(
+            "Dropping session {} given back to a closed session pool ({})",
+            session.get_session_id(),
+            reason
+        );
+    } else {
+        error!(
+            "Cannot give back the session {} ({}): {}",
+            session.get_session_id(),
+            reason,
+            cause()
+        );
+    }
+}
+
+/// The error to return when the pool channels stop working. If the pool
+/// wasn't closed it means that its tasks died, most likely by a panic.
+pub(crate) fn pool_stopped_error(is_closed: &AtomicBool) -> SessionPoolError {
+    if is_closed.load(Relaxed) {
+        SessionPoolError::Closed
+    } else {
+        SessionPoolError::Panicked
+    }
+}
+
+/// Panics carry either a `&str` or a `String` most of the time.
+#[cfg(feature = "internal_pool_with_thread")]
+pub(crate) fn panic_payload_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Waits for the next command of the pool. Once the shutdown signal
+/// fires it returns `PoolCommand::Shutdown`, only once.
+pub(crate) async fn next_pool_command(
+    command_receiver: &Receiver<PoolCommand>,
+    shutdown_signal: &mut Option<ShutdownSignal>,
+    is_closed: &AtomicBool,
+) -> Option<PoolCommand> {
+    let command = match shutdown_signal {
+        Some(signal) => {
+            let signal = signal.0.clone();
+            let receiving = command_receiver.recv();
+            futures::pin_mut!(receiving);
+
+            match select(receiving, signal).await {
+                Either::Left((command, _)) => command,
+                Either::Right(_) => {
+                    *shutdown_signal = None;
+                    return Some(PoolCommand::Shutdown);
+                }
+            }
+        }
+        None => command_receiver.recv().await,
+    };
+
+    match command {
+        Ok(command) => Some(command),
+        // The last handle of the pool was dropped, see `CloseOnDrop`
+        Err(_) if !is_closed.load(Relaxed) => Some(PoolCommand::Shutdown),
+        Err(_) => None,
+    }
+}
+
+pub(crate) fn provide_close_result(sender: &Sender<u16>, still_open: u16) {
+    // This channel should never be full or closed
+    if let Err(err) = sender.try_send(still_open) {
+        error!(
+            "QLDB driver internal error. Cannot return close result due to channel issue: {:?}",
+            err
+        );
+    }
+}
+
+pub(crate) fn provide_stats(sender: &Sender<PoolStats>, stats: PoolStats) {
+    // This channel should never be full or closed
+    if let Err(err) = sender.try_send(stats) {
+        error!(
+            "QLDB driver internal error. Cannot return pool stats due to channel issue: {:?}",
+            err
+        );
+    }
+}
+
+pub(crate) fn provide_idle_session_ids(sender: &Sender<Vec<String>>, session_ids: Vec<String>) {
+    // This channel should never be full or closed
+    if let Err(err) = sender.try_send(session_ids) {
+        error!(
+            "QLDB driver internal error. Cannot return idle session ids due to channel issue: {:?}",
+            err
+        );
+    }
+}
+
+pub(crate) fn provide_drained_count(sender: &Sender<usize>, drained: usize) {
     // This channel should never be full or closed
-    if let Err(err) = sender.try_send(session) {
+    if let Err(err) = sender.try_send(drained) {
         error!(
-            "QLDB driver internal error. Cannot return session due to channel issue: {:?}",
+            "QLDB driver internal error. Cannot return drained sessions count due to channel issue: {:?}",
             err
         );
     }
 }
 
+/// Removes the sessions that are not valid anymore from the idle ones
+/// and returns them, so they can be closed.
+pub(crate) fn take_expired_sessions(sessions: &mut VecDeque<Session>) -> Vec<Session> {
+    let mut expired_sessions = vec![];
+
+    sessions.retain(|session| {
+        if session.is_valid() {
+            true
+        } else {
+            expired_sessions.push(session.clone());
+            false
+        }
+    });
+
+    expired_sessions
+}
+
+/// The number of sessions the pool had when QLDB refused to open one more,
+/// which is used as the maximum until a session is returned to the pool.
+/// Without any session there is nothing to wait for, so then the creations
+/// are retried as with any other error.
+pub(crate) fn session_count_at_server_limit(error: &GetSessionError, session_count: u16) -> Option<u16> {
+    match error {
+        GetSessionError::LimitExceeded(_) if session_count > 0 => Some(session_count),
+        _ => None,
+    }
+}
+
+/// Wakes up `wait_for_no_sessions`, to be called every time the session
+/// count goes down.
+pub(crate) fn notify_session_closed(config: &PoolConfig) {
+    config.session_closed.notify(usize::MAX);
+}
+
+/// Waits until the pool has no sessions open, checking again every time
+/// one is closed.
+pub(crate) async fn wait_for_no_sessions(config: &PoolConfig, session_count: impl Fn() -> u16) {
+    loop {
+        // Listening before checking, so a session closed in between isn't missed
+        let listener = config.session_closed.listen();
+
+        if session_count() == 0 {
+            return;
+        }
+
+        listener.await;
+    }
+}
+
+/// Waits for a place in the queue of waiters for the next request, see
+/// `request_queue_capacity`. The places are freed as the waiters leave the
+/// queue. `remove_canceled` forgets the waiters that stopped waiting, being
+/// ready if there were any, so their places are freed too.
+pub(crate) async fn queue_permit(
+    config: &PoolConfig,
+    mut remove_canceled: impl FnMut(&mut Context<'_>) -> Poll<()>,
+) -> Option<SemaphoreGuardArc> {
+    let permits = config.queue_permits.as_ref()?;
+
+    loop {
+        let acquiring = permits.acquire_arc();
+        futures::pin_mut!(acquiring);
+
+        if let Either::Left((permit, _)) = select(acquiring, poll_fn(&mut remove_canceled)).await {
+            return Some(permit);
+        }
+    }
+}
+
+/// Forgets the waiters that stopped waiting, see `queue_permit`.
+pub(crate) fn remove_canceled_waiters(waiters: &mut VecDeque<SessionSender>, cx: &mut Context<'_>) -> Poll<()> {
+    let waiting = waiters.len();
+
+    // Polling them wakes up the task once one of them stops waiting
+    waiters.retain_mut(|waiter| waiter.poll_canceled(cx).is_pending());
+
+    if waiters.len() < waiting {
+        Poll::Ready(())
+    } else {
+        Poll::Pending
+    }
+}
+
+pub(crate) fn request_session_creation(creating_sender: &Sender<()>) {
+    // The channel is unbounded, so it can only fail when the pool is
+    // closed, and then nobody cares about new sessions anymore.
+    let _ = creating_sender.try_send(());
+}
+
+/// Whether one more session can be created, being `Some(true)` for the
+/// ones over `max_sessions`. The pool only overflows for the requests
+/// waiting, never for `min_idle`, and never over the QLDB limit.
+pub(crate) fn creation_slot(
+    config: &PoolConfig,
+    max_sessions: u16,
+    server_limit: u16,
+    session_count: u16,
+    has_waiters: bool,
+) -> Option<bool> {
+    if session_count < max_sessions.min(server_limit) {
+        return Some(false);
+    }
+
+    let overflow_cap = max_sessions.saturating_add(config.max_overflow).min(server_limit);
+
+    if has_waiters && session_count < overflow_cap {
+        Some(true)
+    } else {
+        None
+    }
+}
+
 pub(crate) async fn create_session(
-    qldb_client: &QldbSessionClient,
+    qldb_client: &Arc<DynQldbSession>,
     ledger_name: &str,
+    pool_id: u64,
+    config: &PoolConfig,
 ) -> Result<Session, GetSessionError> {
     let mut tries: u32 = 0;
 
     let session = loop {
         tries = tries.saturating_add(1);
 
-        match qldb_request_session(qldb_client, ledger_name).await {
+        let requesting = qldb_request_session(qldb_client, ledger_name, config);
+
+        match traced_command("start_session", None, tries, config.observer.as_ref(), requesting).await {
             Ok(session) => break Ok(session),
-            Err(error) if tries > 10 => break Err(error),
+            Err(GetSessionError::Recoverable(error)) if tries > config.max_session_retries => {
+                break Err(GetSessionError::Unrecoverable(error))
+            }
             Err(GetSessionError::Recoverable(_)) => {
-                Timer::after(Duration::from_millis(
-                    tries.saturating_mul(tries).saturating_mul(75).into(),
-                ))
-                .await;
+                let delay = config.retry_delay(tries);
+                traced_backoff("create_session", tries, delay);
+                config.clock.sleep(delay).await;
             }
-            err @ Err(GetSessionError::Unrecoverable(_)) => break err,
+            // Retrying would only fail again until a session is closed
+            err @ Err(GetSessionError::Unrecoverable(_) | GetSessionError::LimitExceeded(_)) => break err,
         }
-    }?;
+    }
+    .inspect_err(|err| {
+        let error = format!("{:#}", err.report());
+        warn!(
+            "Couldn't create a QLDB session for the ledger {}: {}",
+            ledger_name, error
+        );
+        config.last_creation_error.set(Some(error));
+    })?;
+
+    config.last_creation_error.set(None);
+
+    config.observer.on_created(&session);
+    config.events.emit(PoolEvent::SessionCreated {
+        session_id: session.clone(),
+    });
+
+    Ok(Session::new(session, ledger_name, pool_id, config))
+}
+
+/// Ends the session in QLDB, retrying as configured. If it keeps failing
+/// the session is forgotten, QLDB will expire it eventually.
+pub(crate) async fn end_session(qldb_client: &Arc<DynQldbSession>, config: &PoolConfig, session: &Session) {
+    // Held for all the retries, so at most `close_concurrency` sessions are
+    // being closed at once
+    let _permit = match &config.close_permits {
+        Some(permits) => Some(permits.acquire().await),
+        None => None,
+    };
+
+    let mut tries: u32 = 0;
+
+    loop {
+        tries = tries.saturating_add(1);
+
+        let closing = qldb_close_session(qldb_client, session);
+
+        match traced_command(
+            "end_session",
+            Some(session.get_session_id()),
+            tries,
+            session.observer(),
+            closing,
+        )
+        .await
+        {
+            Ok(_) => {
+                config.observer.on_closed(session.get_session_id());
+                emit_session_closed(config, session);
+                break;
+            }
+            // QLDB already forgot about it, so there is nothing to close
+            Err(err) if is_invalid_session_error(&err) => {
+                config.observer.on_closed(session.get_session_id());
+                emit_session_closed(config, session);
+                break;
+            }
+            Err(err) if tries > config.max_close_retries => {
+                warn!(
+                    "Couldn't end the QLDB session {}, QLDB will expire it: {:#}",
+                    session.get_session_id(),
+                    aws_error_report(err)
+                );
+                break;
+            }
+            Err(_) => {
+                let delay = config.retry_delay(tries);
+                traced_backoff("close_session", tries, delay);
+                config.clock.sleep(delay).await;
+            }
+        }
+    }
+}
 
-    Ok(Session::new(session))
+fn emit_session_closed(config: &PoolConfig, session: &Session) {
+    config.events.emit(PoolEvent::SessionClosed {
+        session_id: session.get_session_id().to_string(),
+    });
 }
 
-pub(crate) async fn qldb_close_session(qldb_client: &QldbSessionClient, session: &Session) -> Result<(), eyre::Report> {
+pub(crate) async fn qldb_close_session(
+    qldb_client: &Arc<DynQldbSession>,
+    session: &Session,
+) -> Result<(), RusotoError<SendCommandError>> {
     qldb_client
         .send_command(SendCommandRequest {
             session_token: Some(session.get_session_id().to_string()),
             end_session: Some(EndSessionRequest {}),
             ..Default::default()
         })
+        .compat()
         .await?;
 
     Ok(())
 }
 
+/// Sends a command with only the session token, which QLDB uses to
+/// refresh the session without doing anything else.
+pub(crate) async fn qldb_keep_alive_session(
+    qldb_client: &Arc<DynQldbSession>,
+    session: &Session,
+) -> Result<(), RusotoError<SendCommandError>> {
+    let sending = qldb_client
+        .send_command(SendCommandRequest {
+            session_token: Some(session.get_session_id().to_string()),
+            ..Default::default()
+        })
+        .compat();
+
+    traced_command(
+        "keep_alive",
+        Some(session.get_session_id()),
+        1,
+        session.observer(),
+        sending,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// QLDB can expire a session before we consider it stale, in which case
+/// the session cannot be used anymore.
+pub(crate) fn is_invalid_session_error(err: &RusotoError<SendCommandError>) -> bool {
+    matches!(err, RusotoError::Service(SendCommandError::InvalidSession(_)))
+}
+
+/// Keeps the AWS error code and request id next to the error, see
+/// `AwsErrorMetadata`.
+pub(crate) fn aws_error_report(err: RusotoError<SendCommandError>) -> eyre::Report {
+    match AwsErrorMetadata::from_error(&err) {
+        Some(metadata) => eyre::Report::new(err).wrap_err(metadata),
+        None => eyre::Report::new(err),
+    }
+}
+
 pub(crate) async fn qldb_request_session(
-    qldb_client: &QldbSessionClient,
+    qldb_client: &Arc<DynQldbSession>,
     ledger_name: &str,
+    config: &PoolConfig,
 ) -> Result<String, GetSessionError> {
     match qldb_client
         .send_command(SendCommandRequest {
@@ -79,9 +565,22 @@ pub(crate) async fn qldb_request_session(
                 "Empty session on QLDB response"
             ))),
         },
-        Err(err) => match err {
-            RusotoError::Credentials(_) => Err(GetSessionError::Unrecoverable(eyre::eyre!(err))),
-            _ => Err(GetSessionError::Recoverable(eyre::eyre!(err))),
-        },
+        Err(err) => {
+            let recoverable = match err {
+                // Providers that fetch the credentials remotely (e.g. STS) can fail temporarily
+                RusotoError::Credentials(_) if config.retry_credential_errors => true,
+                _ => config.retry_classifier.is_recoverable(&err),
+            };
+            let limit_exceeded = matches!(err, RusotoError::Service(SendCommandError::LimitExceeded(_)));
+            let report = aws_error_report(err);
+
+            if limit_exceeded {
+                Err(GetSessionError::LimitExceeded(report))
+            } else if recoverable {
+                Err(GetSessionError::Recoverable(report))
+            } else {
+                Err(GetSessionError::Unrecoverable(report))
+            }
+        }
     }
 }
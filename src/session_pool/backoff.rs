@@ -0,0 +1,81 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// Decides how long the session pool waits before retrying a failed
+/// attempt to create or close a session.
+///
+/// `attempt` starts at 1 for the first retry.
+pub trait Backoff: Debug + Send + Sync {
+    fn next_delay(&self, attempt: u32) -> Duration;
+}
+
+/// The backoff used by default. It waits `attempt * attempt * 75`
+/// milliseconds between retries, as `PolynomialBackoff::default()`.
+#[derive(Debug, Clone, Default)]
+pub struct DefaultBackoff;
+
+impl Backoff for DefaultBackoff {
+    fn next_delay(&self, attempt: u32) -> Duration {
+        PolynomialBackoff::default().next_delay(attempt)
+    }
+}
+
+/// Waits `base_delay * attempt ^ exponent` between retries, so the first
+/// retry and how fast the delays grow can be tuned on their own, e.g. a
+/// 10 ms first retry for transient blips that still grows quadratically.
+/// An exponent of 0 waits the same time before every retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PolynomialBackoff {
+    base_delay: Duration,
+    exponent: u32,
+}
+
+impl PolynomialBackoff {
+    pub fn new(base_delay: Duration, exponent: u32) -> PolynomialBackoff {
+        PolynomialBackoff { base_delay, exponent }
+    }
+}
+
+impl Default for PolynomialBackoff {
+    fn default() -> Self {
+        PolynomialBackoff::new(Duration::from_millis(75), 2)
+    }
+}
+
+impl Backoff for PolynomialBackoff {
+    fn next_delay(&self, attempt: u32) -> Duration {
+        self.base_delay.saturating_mul(attempt.saturating_pow(self.exponent))
+    }
+}
+
+/// Randomizes the delays between retries, so many clients that failed at
+/// the same time, e.g. when QLDB was not reachable, don't retry all at once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Jitter {
+    /// The delays are used as the backoff returns them.
+    #[default]
+    None,
+    /// Waits a random time between zero and the delay.
+    Full,
+    /// Waits half of the delay plus a random time up to the other half.
+    Equal,
+}
+
+impl Jitter {
+    pub(crate) fn apply(self, delay: Duration) -> Duration {
+        match self {
+            Jitter::None => delay,
+            Jitter::Full => random_up_to(delay),
+            Jitter::Equal => {
+                let half = delay / 2;
+                half + random_up_to(delay - half)
+            }
+        }
+    }
+}
+
+fn random_up_to(max: Duration) -> Duration {
+    let max_nanos = u64::try_from(max.as_nanos()).unwrap_or(u64::MAX);
+
+    Duration::from_nanos(fastrand::u64(0..=max_nanos))
+}
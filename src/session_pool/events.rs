@@ -0,0 +1,82 @@
+use async_channel::{bounded, Receiver, Sender};
+use std::sync::Mutex;
+
+/// How many events a subscriber can fall behind. Once its channel is full
+/// the next events are dropped for it, so a slow subscriber never slows
+/// down the pool.
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// Something that happened in the session pool, see `QldbClient::subscribe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolEvent {
+    SessionCreated {
+        session_id: String,
+    },
+    SessionClosed {
+        session_id: String,
+    },
+    /// A session was handed to a request.
+    SessionBorrowed {
+        session_id: String,
+    },
+    /// A session in use came back to the pool.
+    SessionReturned {
+        session_id: String,
+    },
+    /// A request has to wait, as there is no idle session.
+    RequestQueued,
+    /// The pool gave up creating a session, after the configured retries.
+    CreationFailed {
+        error: String,
+    },
+}
+
+/// The subscribers of a pool. Every subscriber gets every event.
+#[derive(Debug, Default)]
+pub(crate) struct PoolEvents {
+    subscribers: Mutex<Vec<Sender<PoolEvent>>>,
+}
+
+impl PoolEvents {
+    pub(crate) fn subscribe(&self) -> Receiver<PoolEvent> {
+        let (sender, receiver) = bounded(SUBSCRIBER_CAPACITY);
+
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(sender);
+
+        receiver
+    }
+
+    pub(crate) fn emit(&self, event: PoolEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Forget about the ones that dropped their stream
+        subscribers.retain(|subscriber| !subscriber.is_closed());
+
+        for subscriber in subscribers.iter() {
+            let _ = subscriber.try_send(event.clone());
+        }
+    }
+}
+
+/// The error of the last session creation, while the pool cannot create
+/// sessions. It is cleared once a session is created again.
+#[derive(Debug, Default)]
+pub(crate) struct LastCreationError {
+    error: Mutex<Option<String>>,
+}
+
+impl LastCreationError {
+    pub(crate) fn set(&self, error: Option<String>) {
+        *self.error.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = error;
+    }
+
+    pub(crate) fn get(&self) -> Option<String> {
+        self.error
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
@@ -1,48 +1,666 @@
+#[cfg(feature = "internal_pool_with_thread")]
 mod agnostic_async_pool_monothread;
+#[cfg(feature = "internal_pool_with_spawner")]
 mod agnostic_async_pool_multithread;
 mod agnostic_async_pool_shared;
+mod backoff;
+mod clock;
+mod events;
+mod observer;
+mod pooled_session;
+mod retry_classifier;
 #[cfg(feature = "internal_pool_with_spawner")]
 mod session_pool_spawner;
 #[cfg(feature = "internal_pool_with_thread")]
 mod session_pool_thread;
+mod session_validator;
 
-use log::error;
+use crate::{QldbError, QldbResult};
+use async_channel::{Receiver, Sender};
+use async_lock::{Semaphore, SemaphoreGuardArc};
+pub use backoff::{Backoff, DefaultBackoff, Jitter, PolynomialBackoff};
+pub use clock::{Clock, ManualClock, SystemClock};
+use event_listener::Event;
+pub use events::PoolEvent;
+pub(crate) use events::{LastCreationError, PoolEvents};
+use futures::channel::oneshot;
+use futures::future::Shared;
+pub use observer::{CommandMetrics, NoopSessionObserver, SessionObserver};
+pub use pooled_session::PooledSession;
+pub use retry_classifier::{DefaultRetryClassifier, RetryClassifier};
+use rusoto_qldb_session::QldbSession;
 #[cfg(feature = "internal_pool_with_spawner")]
 pub use session_pool_spawner::SpawnerSessionPool;
 #[cfg(feature = "internal_pool_with_thread")]
+pub(crate) use session_pool_thread::PoolThread;
+#[cfg(feature = "internal_pool_with_thread")]
 pub use session_pool_thread::ThreadedSessionPool;
+pub use session_validator::{DefaultSessionValidator, SessionValidator};
+use std::fmt;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, Ordering::Relaxed};
 use std::sync::Arc;
-use std::{future::Future, time::Instant};
+use std::task::{Context, Poll};
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+/// QLDB sessions expire after some time, 10 minutes is what we have
+/// been using as a safe value so far.
+pub(crate) const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(10 * 60);
+
+pub(crate) const DEFAULT_MAX_SESSION_RETRIES: u32 = 10;
+
+/// Closing is best effort, QLDB expires the sessions that we couldn't close.
+pub(crate) const DEFAULT_MAX_CLOSE_RETRIES: u32 = 2;
+
+/// How many sessions `get_retrying` discards before giving up, more than
+/// that most likely means that something else is going on.
+pub(crate) const MAX_INVALID_SESSION_RETRIES: u32 = 3;
+
+/// How long the pool waits after giving up on creating a session before
+/// it tries again, so a QLDB outage doesn't turn into a retry loop.
+pub(crate) const DEFAULT_CREATION_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+pub(crate) const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Same as the QLDB transaction timeout, after that the sessions
+/// in use are most likely forgotten.
+pub(crate) const DEFAULT_CLOSE_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// The commands are answered one at a time, a flood of `stats` calls
+/// shouldn't grow the memory used by the pool without limit.
+pub(crate) const DEFAULT_COMMAND_QUEUE_CAPACITY: usize = 64;
 
+/// Which idle session the pool hands out first.
+///
+/// - `LeastRecentlyUsed` rotates over all the idle sessions, spreading the
+///   work between them. The sessions handed out are the ones that waited the
+///   longest, so with a `session_ttl` close to the QLDB session lifetime they
+///   are the most likely to be already expired by QLDB.
+/// - `MostRecentlyUsed` keeps reusing the sessions that just worked, leaving
+///   the rest idle until the pool closes them once `session_ttl` passes. It
+///   is the one that minimizes the stale sessions discarded by
+///   `SessionPool::get_retrying` and by transactions.
+///
+/// Newly created sessions are always handed out before the idle ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReusePolicy {
+    #[default]
+    LeastRecentlyUsed,
+    MostRecentlyUsed,
+}
+
+impl ReusePolicy {
+    /// Whether a session given back needs to wait for all the other idle
+    /// sessions before being used again.
+    pub(crate) fn returns_to_front(self) -> bool {
+        self == ReusePolicy::LeastRecentlyUsed
+    }
+}
+
+/// The order in which the requests waiting for a session are served. The
+/// `High` ones are served before any `Normal` one, e.g. for user facing
+/// reads that shouldn't wait behind the background jobs. Within the same
+/// priority they are served in the order they arrived.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+}
+
+static NEXT_POOL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Every session pool gets its own id, so the sessions it creates can be
+/// told apart from the ones of other pools.
+pub(crate) fn next_pool_id() -> u64 {
+    NEXT_POOL_ID.fetch_add(1, Relaxed)
+}
+
+/// Tunables shared by all the session pool implementations.
 #[derive(Debug, Clone)]
+pub(crate) struct PoolConfig {
+    pub session_ttl: Duration,
+    pub max_session_retries: u32,
+    pub max_close_retries: u32,
+    pub backoff: Arc<dyn Backoff>,
+    pub min_idle: u16,
+    pub max_idle: Option<u16>,
+    pub keep_alive_interval: Option<Duration>,
+    pub sweep_interval: Duration,
+    pub close_grace_period: Duration,
+    pub request_queue_capacity: Option<usize>,
+    pub command_queue_capacity: usize,
+    pub observer: Arc<dyn SessionObserver>,
+    pub retry_credential_errors: bool,
+    pub retry_classifier: Arc<dyn RetryClassifier>,
+    pub session_validator: Arc<dyn SessionValidator>,
+    pub creation_concurrency: u16,
+    pub max_overflow: u16,
+    pub reuse_policy: ReusePolicy,
+    pub max_backoff: Option<Duration>,
+    pub jitter: Jitter,
+    pub clock: Arc<dyn Clock>,
+    pub max_session_uses: Option<u32>,
+    pub creation_retry_delay: Duration,
+    pub shutdown_signal: Option<ShutdownSignal>,
+    pub close_concurrency: Option<usize>,
+    // Replaced by every pool, so the pools created from the same config
+    // don't share their subscribers, their permits nor their stats
+    pub events: Arc<PoolEvents>,
+    pub close_permits: Option<Arc<Semaphore>>,
+    pub queue_permits: Option<Arc<Semaphore>>,
+    pub last_creation_error: Arc<LastCreationError>,
+    pub served: Arc<ServedCounters>,
+    pub session_closed: Arc<Event>,
+    pub warming: Arc<Warming>,
+}
+
+impl PoolConfig {
+    pub fn validate(&self) -> QldbResult<()> {
+        if self.sweep_interval.is_zero() {
+            return Err(QldbError::InvalidPoolConfiguration(
+                "sweep_interval cannot be zero".to_string(),
+            ));
+        }
+
+        if self.max_session_retries < 1 {
+            return Err(QldbError::InvalidPoolConfiguration(
+                "max_session_retries needs to be at least 1".to_string(),
+            ));
+        }
+
+        if self.creation_concurrency < 1 {
+            return Err(QldbError::InvalidPoolConfiguration(
+                "creation_concurrency needs to be at least 1".to_string(),
+            ));
+        }
+
+        if self.max_session_uses == Some(0) {
+            return Err(QldbError::InvalidPoolConfiguration(
+                "max_session_uses cannot be zero".to_string(),
+            ));
+        }
+
+        if let Some(max_idle) = self.max_idle {
+            if max_idle < self.min_idle {
+                return Err(QldbError::InvalidPoolConfiguration(format!(
+                    "max_idle ({}) cannot be lower than min_idle ({})",
+                    max_idle, self.min_idle
+                )));
+            }
+        }
+
+        if self.close_concurrency == Some(0) {
+            return Err(QldbError::InvalidPoolConfiguration(
+                "close_concurrency cannot be zero".to_string(),
+            ));
+        }
+
+        if self.request_queue_capacity == Some(0) {
+            return Err(QldbError::InvalidPoolConfiguration(
+                "request_queue_capacity cannot be zero".to_string(),
+            ));
+        }
+
+        if self.command_queue_capacity == 0 {
+            return Err(QldbError::InvalidPoolConfiguration(
+                "command_queue_capacity cannot be zero".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The config of a new pool, with its own subscribers, close and queue
+    /// permits, creation error, served counters and close notifications.
+    pub fn for_new_pool(self) -> PoolConfig {
+        PoolConfig {
+            events: Arc::new(PoolEvents::default()),
+            last_creation_error: Arc::new(LastCreationError::default()),
+            served: Arc::new(ServedCounters::default()),
+            session_closed: Arc::new(Event::new()),
+            warming: Arc::new(Warming::default()),
+            close_permits: self
+                .close_concurrency
+                .map(|concurrency| Arc::new(Semaphore::new(concurrency))),
+            queue_permits: self
+                .request_queue_capacity
+                .map(|capacity| Arc::new(Semaphore::new(capacity))),
+            ..self
+        }
+    }
+
+    /// How long to wait before the given retry, never longer than
+    /// `max_backoff`.
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        let delay = self.backoff.next_delay(attempt);
+
+        let delay = match self.max_backoff {
+            Some(max_backoff) => delay.min(max_backoff),
+            None => delay,
+        };
+
+        self.jitter.apply(delay)
+    }
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            session_ttl: DEFAULT_SESSION_TTL,
+            max_session_retries: DEFAULT_MAX_SESSION_RETRIES,
+            max_close_retries: DEFAULT_MAX_CLOSE_RETRIES,
+            backoff: Arc::new(DefaultBackoff),
+            min_idle: 0,
+            max_idle: None,
+            keep_alive_interval: None,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            close_grace_period: DEFAULT_CLOSE_GRACE_PERIOD,
+            request_queue_capacity: None,
+            command_queue_capacity: DEFAULT_COMMAND_QUEUE_CAPACITY,
+            observer: Arc::new(NoopSessionObserver),
+            retry_credential_errors: false,
+            retry_classifier: Arc::new(DefaultRetryClassifier),
+            session_validator: Arc::new(DefaultSessionValidator),
+            creation_concurrency: 1,
+            max_overflow: 0,
+            reuse_policy: ReusePolicy::default(),
+            max_backoff: None,
+            jitter: Jitter::default(),
+            clock: Arc::new(SystemClock),
+            max_session_uses: None,
+            creation_retry_delay: DEFAULT_CREATION_RETRY_DELAY,
+            shutdown_signal: None,
+            close_concurrency: None,
+            events: Arc::new(PoolEvents::default()),
+            close_permits: None,
+            queue_permits: None,
+            last_creation_error: Arc::new(LastCreationError::default()),
+            served: Arc::new(ServedCounters::default()),
+            session_closed: Arc::new(Event::new()),
+            warming: Arc::new(Warming::default()),
+        }
+    }
+}
+
+#[derive(Clone)]
 struct InnerSession {
     created_on_instant: Instant,
     session_id: String,
+    ledger_name: String,
+    pool_id: u64,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    observer: Arc<dyn SessionObserver>,
+    validator: Arc<dyn SessionValidator>,
+    // Shared with the refreshed copies, as they are the same session
+    uses: Arc<AtomicU32>,
+    max_uses: Option<u32>,
 }
 
-#[derive(Debug, Clone)]
+/// A QLDB session opened by the pool, as seen by a `SessionValidator`.
+#[derive(Clone)]
 pub struct Session {
     inner: Arc<InnerSession>,
 }
 
+/// How much of the session id is shown by `Debug`. The id is the token
+/// used to send commands with the session, so it shouldn't end up in logs.
+const DEBUG_SESSION_ID_PREFIX: usize = 8;
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let session_id: String = self.inner.session_id.chars().take(DEBUG_SESSION_ID_PREFIX).collect();
+
+        f.debug_struct("Session")
+            .field("session_id", &format_args!("{}...", session_id))
+            .field("ledger_name", &self.inner.ledger_name)
+            .field("pool_id", &self.inner.pool_id)
+            .field("age", &self.age())
+            .field("uses", &self.uses())
+            .finish()
+    }
+}
+
 impl Session {
-    pub fn new(session_id: String) -> Session {
+    pub(crate) fn new(session_id: String, ledger_name: &str, pool_id: u64, config: &PoolConfig) -> Session {
         Session {
             inner: Arc::new(InnerSession {
-                created_on_instant: Instant::now(),
+                created_on_instant: config.clock.now(),
                 session_id,
+                ledger_name: ledger_name.to_string(),
+                pool_id,
+                ttl: config.session_ttl,
+                clock: config.clock.clone(),
+                observer: config.observer.clone(),
+                validator: config.session_validator.clone(),
+                uses: Arc::new(AtomicU32::new(0)),
+                max_uses: config.max_session_uses,
             }),
         }
     }
 
+    /// The whole session token, which allows to send commands with the
+    /// session. `Debug` only shows its beginning, so it isn't logged by
+    /// mistake.
     pub fn get_session_id(&self) -> &str {
         &self.inner.session_id
     }
 
+    /// The session pool that created the session.
+    pub(crate) fn get_pool_id(&self) -> u64 {
+        self.inner.pool_id
+    }
+
+    /// The observer of the pool, told about the commands sent with the
+    /// session.
+    pub(crate) fn observer(&self) -> &dyn SessionObserver {
+        self.inner.observer.as_ref()
+    }
+
+    /// The ledger the session was opened for.
+    pub fn get_ledger_name(&self) -> &str {
+        &self.inner.ledger_name
+    }
+
+    /// Returns the same session as if it was just created. Used
+    /// once QLDB confirms that the session is still alive.
+    pub(crate) fn refreshed(&self) -> Session {
+        Session {
+            inner: Arc::new(InnerSession {
+                created_on_instant: self.inner.clock.now(),
+                session_id: self.inner.session_id.clone(),
+                ledger_name: self.inner.ledger_name.clone(),
+                pool_id: self.inner.pool_id,
+                ttl: self.inner.ttl,
+                clock: self.inner.clock.clone(),
+                observer: self.inner.observer.clone(),
+                validator: self.inner.validator.clone(),
+                uses: self.inner.uses.clone(),
+                max_uses: self.inner.max_uses,
+            }),
+        }
+    }
+
+    /// Returns the same session but worn out after one use, so the pool
+    /// closes it once it is given back instead of keeping it idle.
+    pub(crate) fn single_use(&self) -> Session {
+        Session {
+            inner: Arc::new(InnerSession {
+                created_on_instant: self.inner.created_on_instant,
+                session_id: self.inner.session_id.clone(),
+                ledger_name: self.inner.ledger_name.clone(),
+                pool_id: self.inner.pool_id,
+                ttl: self.inner.ttl,
+                clock: self.inner.clock.clone(),
+                observer: self.inner.observer.clone(),
+                validator: self.inner.validator.clone(),
+                uses: self.inner.uses.clone(),
+                max_uses: Some(self.uses().saturating_add(1)),
+            }),
+        }
+    }
+
+    /// Returns the same session but already expired, so the pool closes
+    /// it as soon as it is given back.
+    pub(crate) fn invalidated(&self) -> Session {
+        Session {
+            inner: Arc::new(InnerSession {
+                created_on_instant: self.inner.created_on_instant,
+                session_id: self.inner.session_id.clone(),
+                ledger_name: self.inner.ledger_name.clone(),
+                pool_id: self.inner.pool_id,
+                ttl: Duration::ZERO,
+                clock: self.inner.clock.clone(),
+                observer: self.inner.observer.clone(),
+                validator: self.inner.validator.clone(),
+                uses: self.inner.uses.clone(),
+                max_uses: self.inner.max_uses,
+            }),
+        }
+    }
+
+    /// Whether the pool can still hand out the session: it is younger than
+    /// the ttl, it wasn't used more than the max uses and the
+    /// `SessionValidator` accepts it.
     pub fn is_valid(&self) -> bool {
-        self.inner.created_on_instant.elapsed().as_secs() < 10 * 60
+        let is_worn_out = match self.inner.max_uses {
+            Some(max_uses) => self.uses() >= max_uses,
+            None => false,
+        };
+
+        !is_worn_out && self.age() < self.inner.ttl && self.inner.validator.is_valid(self)
+    }
+
+    /// How many times the session has been given back to the pool after
+    /// being used.
+    pub fn uses(&self) -> u32 {
+        self.inner.uses.load(Relaxed)
+    }
+
+    /// Called by the pool when the session comes back. Counting them then,
+    /// instead of when they are handed out, skips the sessions that never
+    /// reached their requester.
+    pub(crate) fn record_use(&self) {
+        self.inner.uses.fetch_add(1, Relaxed);
     }
+
+    /// How long ago the session was created, or last confirmed alive.
+    pub fn age(&self) -> Duration {
+        self.inner
+            .clock
+            .now()
+            .saturating_duration_since(self.inner.created_on_instant)
+    }
+
+    /// How long until the session passes its ttl. Zero once it did.
+    pub fn remaining_ttl(&self) -> Duration {
+        self.inner.ttl.saturating_sub(self.age())
+    }
+}
+
+/// A snapshot of the state of the session pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Sessions currently open (or being opened), both idle and in use.
+    pub active: u16,
+    /// Sessions waiting in the pool to be used.
+    pub idle: usize,
+    /// Requests waiting for a session to be available.
+    pub waiters: usize,
+    /// Maximum number of sessions the pool can have open.
+    pub max_sessions: u16,
+    /// Sessions created over `max_sessions` since the pool started, see
+    /// `QldbClientBuilder::allow_overflow`.
+    pub overflow_sessions_created: u64,
+    /// Requests served with a session that was already open since the
+    /// pool started, either idle or given back by someone else.
+    pub served_from_pool: u64,
+    /// Requests that had to wait for a new session since the pool started.
+    pub served_from_new: u64,
+}
+
+impl PoolStats {
+    /// All the sessions are in use and the pool is at its max sessions, so
+    /// new requests have to wait, or get an overflow session if allowed.
+    pub fn is_saturated(&self) -> bool {
+        self.idle == 0 && self.active >= self.max_sessions
+    }
+
+    /// The share of the requests served with a session already open, from 0
+    /// to 1. A low ratio means that the pool is too small or the sessions
+    /// expire too fast. None until the pool serves a request.
+    pub fn reuse_ratio(&self) -> Option<f64> {
+        let served = self.served_from_pool.saturating_add(self.served_from_new);
+
+        if served == 0 {
+            None
+        } else {
+            Some(self.served_from_pool as f64 / served as f64)
+        }
+    }
+}
+
+/// How the requests of a pool were served, see `PoolStats`.
+#[derive(Debug, Default)]
+pub(crate) struct ServedCounters {
+    pub(crate) from_pool: AtomicU64,
+    pub(crate) from_new: AtomicU64,
+}
+
+/// The sessions that `SessionPool::warm` asked for and that are still to
+/// be created. The creator task treats them like the missing `min_idle`
+/// ones, but only until they are created.
+#[derive(Debug, Default)]
+pub(crate) struct Warming {
+    missing: AtomicU16,
+    created: Event,
+}
+
+impl Warming {
+    /// Replaces the sessions asked for by the previous warm, if any.
+    pub(crate) fn request(&self, missing: u16) {
+        self.missing.store(missing, Relaxed);
+        self.created.notify(usize::MAX);
+    }
+
+    pub(crate) fn missing(&self) -> u16 {
+        self.missing.load(Relaxed)
+    }
+
+    /// Counts a session created, for the warm or not, as all of them count
+    /// for the sessions open.
+    pub(crate) fn session_created(&self) {
+        let previous = self
+            .missing
+            .fetch_update(Relaxed, Relaxed, |missing| missing.checked_sub(1));
+
+        if previous == Ok(1) {
+            self.created.notify(usize::MAX);
+        }
+    }
+
+    /// Waits until no session is missing, either because they were created
+    /// or because the request was replaced.
+    pub(crate) async fn wait(&self) {
+        loop {
+            // Listening before checking, so a session created in between isn't missed
+            let listener = self.created.listen();
+
+            if self.missing() == 0 {
+                return;
+            }
+
+            listener.await;
+        }
+    }
+}
+
+/// A future that closes the pools once it completes. It is shared, so all
+/// the pools created from the same config, e.g. with `for_ledger`, close
+/// with it.
+#[derive(Clone)]
+pub(crate) struct ShutdownSignal(pub Shared<Pin<Box<dyn Future<Output = ()> + Send>>>);
+
+impl fmt::Debug for ShutdownSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ShutdownSignal")
+    }
+}
+
+/// Where the pool sends the session a request is waiting for. A request is
+/// answered only once, so a oneshot channel is enough.
+#[derive(Debug)]
+pub(crate) struct SessionSender {
+    sender: oneshot::Sender<Session>,
+    priority: Priority,
+    events: Arc<PoolEvents>,
+    // Its place in the queue of waiters, freed once it leaves the queue
+    _queue_permit: Option<SemaphoreGuardArc>,
+}
+
+impl SessionSender {
+    pub(crate) fn new(sender: oneshot::Sender<Session>, priority: Priority, events: Arc<PoolEvents>) -> SessionSender {
+        SessionSender {
+            sender,
+            priority,
+            events,
+            _queue_permit: None,
+        }
+    }
+
+    /// Takes the place in the queue of waiters, see `request_queue_capacity`.
+    pub(crate) fn with_queue_permit(self, queue_permit: Option<SemaphoreGuardArc>) -> SessionSender {
+        SessionSender {
+            _queue_permit: queue_permit,
+            ..self
+        }
+    }
+
+    /// Gives the session back as error if the requester is gone.
+    pub(crate) fn send(self, session: Session) -> Result<(), Session> {
+        let session_id = session.get_session_id().to_string();
+
+        self.sender.send(session)?;
+
+        // Here, as every session handed out to a waiter goes through it
+        self.events.emit(PoolEvent::SessionBorrowed { session_id });
+
+        Ok(())
+    }
+
+    pub(crate) fn is_canceled(&self) -> bool {
+        self.sender.is_canceled()
+    }
+
+    /// Ready once the requester is gone, waking up the task otherwise.
+    pub(crate) fn poll_canceled(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.sender.poll_canceled(cx)
+    }
+
+    pub(crate) fn priority(&self) -> Priority {
+        self.priority
+    }
+}
+
+/// Anything able to send QLDB commands. It is usually a `QldbSessionClient`,
+/// but it can be replaced by a fake one, so the pool logic can be tested
+/// without AWS.
+pub(crate) type DynQldbSession = dyn QldbSession + Send + Sync;
+
+/// Held by every clone of a pool. Once the last one is dropped without
+/// closing the pool, it closes it, so its idle sessions are ended in QLDB
+/// instead of being left open until they expire.
+#[derive(Debug)]
+pub(crate) struct CloseOnDrop(pub Sender<PoolCommand>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        // Unlike sending `Shutdown`, closing the channel cannot fail when it
+        // is full. The command task shuts the pool down once it handled the
+        // commands left, if the pool wasn't already closed.
+        self.0.close();
+    }
+}
+
+/// Requests that are answered by the pool tasks, as they are the
+/// ones owning the pool state.
+pub(crate) enum PoolCommand {
+    Close(Sender<u16>),
+    /// Same as `Close` but nobody waits for the result. It comes from the
+    /// shutdown signal or from dropping the last handle of the pool.
+    Shutdown,
+    DrainIdle(Sender<usize>),
+    IdleSessionIds(Sender<Vec<String>>),
+    SetMax(u16),
+    /// Asks for the sessions missing to have this many open, answering
+    /// once they are asked for. See `Warming`.
+    Warm(u16, Sender<()>),
+    Stats(Sender<PoolStats>),
+    TryRequest(Sender<Option<Session>>),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -51,17 +669,141 @@ pub(crate) enum GetSessionError {
     Unrecoverable(eyre::Report),
     #[error("The QLDB command returned an error")]
     Recoverable(eyre::Report),
+    /// QLDB won't open more sessions for the ledger until some are closed
+    #[error("The QLDB session limit of the ledger was reached")]
+    LimitExceeded(eyre::Report),
+}
+
+impl GetSessionError {
+    /// The error returned by QLDB.
+    pub(crate) fn report(&self) -> &eyre::Report {
+        match self {
+            GetSessionError::Unrecoverable(report)
+            | GetSessionError::Recoverable(report)
+            | GetSessionError::LimitExceeded(report) => report,
+        }
+    }
+}
+
+/// Why the session pool couldn't provide a session. They end up as the
+/// source of the `eyre::Report` returned by the pool methods.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum SessionPoolError {
+    #[error("Session pool closed")]
+    Closed,
+    #[error("The session pool stopped unexpectedly, its tasks are not running anymore")]
+    Panicked,
 }
 
 #[async_trait::async_trait]
 pub trait SessionPool: Send + Sync {
-    async fn close(&self);
+    /// Stops providing sessions and closes all of them, waiting for the ones
+    /// in use to be returned. It fails if some sessions couldn't be closed
+    /// within the grace period.
+    ///
+    /// Only the first call closes the pool. The next ones, from any clone,
+    /// wait for that same close to finish and return the same result.
+    async fn close(&self) -> eyre::Result<()>;
 
+    /// Completes once the pool starts closing, whoever called `close`, or
+    /// if its tasks stopped unexpectedly. Useful for background tasks that
+    /// need to stop asking for sessions.
+    async fn closed(&self);
+
+    /// Returns an idle session or waits for one. When all the sessions are
+    /// in use the requests are served in the same order they arrived.
     async fn get(&self) -> eyre::Result<Session>;
 
+    /// Same as `get` but the session is given back automatically once
+    /// the returned guard is dropped.
+    async fn get_guard(&self) -> eyre::Result<PooledSession>;
+
+    /// Same as `get` but the request is served before the ones with a
+    /// lower priority, see `Priority`.
+    async fn get_with_priority(&self, priority: Priority) -> eyre::Result<Session>;
+
+    /// Gets `n` sessions at once. If one of them cannot be obtained the
+    /// ones already obtained are given back and the error is returned.
+    /// Over `max_sessions` the requests wait as usual, until other sessions
+    /// are given back.
+    async fn get_many(&self, n: usize) -> eyre::Result<Vec<Session>>;
+
+    /// Same as `get` but it checks with QLDB that the session is still
+    /// alive. Sessions that QLDB already expired are discarded and replaced
+    /// by other ones, a few times at most.
+    async fn get_retrying(&self) -> eyre::Result<Session>;
+
+    /// Same as `get` but it returns None if no session is available
+    /// after waiting for `timeout`.
+    async fn get_with_timeout(&self, timeout: Duration) -> eyre::Result<Option<Session>>;
+
+    /// Returns an idle session if there is one, without waiting and
+    /// without creating new sessions.
+    async fn try_get(&self) -> eyre::Result<Option<Session>>;
+
+    async fn stats(&self) -> eyre::Result<PoolStats>;
+
+    /// Why the last session creation failed, while the pool cannot create
+    /// sessions. None once a session is created again.
+    fn last_creation_error(&self) -> Option<String>;
+
+    /// The ids of the sessions waiting in the pool to be used, in no
+    /// particular order. It doesn't change which session is used next.
+    async fn idle_session_ids(&self) -> eyre::Result<Vec<String>>;
+
+    /// Returns a new channel that receives everything that happens in the
+    /// pool from now on. Dropping it unsubscribes.
+    fn subscribe(&self) -> Receiver<PoolEvent>;
+
+    /// Closes the idle sessions and returns how many of them there were.
+    /// The sessions in use are left alone and the pool keeps working,
+    /// opening new sessions as they are needed again.
+    async fn drain_idle(&self) -> eyre::Result<usize>;
+
+    /// How many requests are waiting for a session, including the ones
+    /// queued that the pool didn't process yet.
+    async fn pending_requests(&self) -> eyre::Result<usize>;
+
+    /// Changes the maximum number of sessions. Lowering it doesn't close the
+    /// sessions already open, it only stops creating new ones until enough
+    /// of them are closed.
+    async fn set_max_sessions(&self, new_max: u16) -> eyre::Result<()>;
+
+    /// Opens the sessions missing to have `target` open, counting the ones
+    /// in use, and waits until they are created. It doesn't check out any
+    /// session, so it doesn't count as serving a request. It returns false
+    /// if they weren't created within `timeout`.
+    async fn warm(&self, target: u16, timeout: Duration) -> eyre::Result<bool>;
+
+    /// Same as `give_back` but it fails if the pool cannot take the session
+    /// anymore, e.g. because it was already closed and stopped. In that
+    /// case the session is still open in QLDB, so keep a clone of it if you
+    /// want to close it yourself.
+    async fn give_back_checked(&self, session: Session) -> eyre::Result<()>;
+
+    /// Returns a session to the pool without waiting. If the pool cannot
+    /// take it the session is dropped, until QLDB expires it.
+    ///
+    /// The sessions given back are never limited like the requests and the
+    /// commands, so it never waits nor drops sessions under load: there
+    /// can't be more sessions coming back than the ones open. The pool only
+    /// can't take them once it stopped.
     fn give_back(&self, session: Session);
+
+    /// Closes a session that shouldn't be used anymore instead of giving it
+    /// back, e.g. because QLDB returned `InvalidSessionException` for it,
+    /// making room for a new one. `give_back` only checks the session ttl,
+    /// so it cannot know that QLDB invalidated the session.
+    ///
+    /// It goes through the same channel as `give_back`, with the same
+    /// guarantees.
+    fn discard(&self, session: Session);
 }
 
+/// Creates the session pool of a ledger the same way the pool of the client
+/// was created: with the same QLDB client, configuration and thread or spawner.
+pub(crate) type SessionPoolFactory = Arc<dyn Fn(&str) -> Arc<dyn SessionPool> + Send + Sync>;
+
 pub type SpawnerFnMonothread = Arc<dyn Fn(Pin<Box<dyn Future<Output = ()>>>)>;
 
 pub type SpawnerFnMonoMultithread = Arc<dyn Fn(Pin<Box<dyn Future<Output = ()> + Send>>) + Send + Sync>;
@@ -0,0 +1,42 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// Gets notified every time the session pool opens or closes a session
+/// in QLDB, e.g. to emit metrics or traces.
+///
+/// The methods are called from the session pool tasks, so they should
+/// return quickly and leave any slow work to somebody else.
+pub trait SessionObserver: Debug + Send + Sync {
+    /// Called once QLDB has started the session.
+    fn on_created(&self, _session_id: &str) {}
+
+    /// Called once QLDB has ended the session, or when QLDB reports a
+    /// session as no longer valid and the pool discards it.
+    fn on_closed(&self, _session_id: &str) {}
+
+    /// Called after every request sent to QLDB, e.g. to build latency
+    /// histograms. Called from the tasks of both the pool and the
+    /// transactions.
+    fn on_command(&self, _metrics: &CommandMetrics) {}
+}
+
+/// How a request sent to QLDB went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandMetrics {
+    /// The QLDB command, e.g. `start_session` or `execute_statement`.
+    pub command: &'static str,
+    /// How long QLDB took to answer, or to fail.
+    pub latency: Duration,
+    /// 1 for the first try. The commands that are retried, like starting
+    /// a session, report every try, so the last one tells how many tries
+    /// it took. The commands of a transaction run by `QldbClient::transact`
+    /// report the try of the transaction.
+    pub attempt: u32,
+    pub succeeded: bool,
+}
+
+/// The observer used by default. It does nothing.
+#[derive(Debug, Clone, Default)]
+pub struct NoopSessionObserver;
+
+impl SessionObserver for NoopSessionObserver {}
@@ -0,0 +1,50 @@
+use crate::session_pool::{Session, SessionPool};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// A session taken from the pool that goes back to it once dropped, so
+/// early returns and panics don't leak sessions out of the pool.
+pub struct PooledSession {
+    session: Session,
+    // None once the session has been taken out of the guard
+    session_pool: Option<Arc<dyn SessionPool>>,
+}
+
+impl PooledSession {
+    pub(crate) fn new(session: Session, session_pool: Arc<dyn SessionPool>) -> PooledSession {
+        PooledSession {
+            session,
+            session_pool: Some(session_pool),
+        }
+    }
+
+    /// Takes the session out of the guard. From then on it is the caller
+    /// who needs to give it back to the pool.
+    pub fn into_session(mut self) -> Session {
+        self.session_pool = None;
+        self.session.clone()
+    }
+}
+
+impl Deref for PooledSession {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl Drop for PooledSession {
+    fn drop(&mut self) {
+        if let Some(session_pool) = self.session_pool.take() {
+            session_pool.give_back(self.session.clone());
+        }
+    }
+}
+
+impl fmt::Debug for PooledSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledSession").field("session", &self.session).finish()
+    }
+}
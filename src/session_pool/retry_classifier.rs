@@ -0,0 +1,22 @@
+use rusoto_core::RusotoError;
+use rusoto_qldb_session::SendCommandError;
+use std::fmt::Debug;
+
+/// Decides which errors are worth retrying when the session pool fails to
+/// create a session. The ones that are not fail the creation right away,
+/// e.g. when the ledger doesn't exist, instead of being retried with
+/// backoff until `max_session_retries`.
+pub trait RetryClassifier: Debug + Send + Sync {
+    fn is_recoverable(&self, err: &RusotoError<SendCommandError>) -> bool;
+}
+
+/// The classifier used by default. Every error is retried except the
+/// credential ones, see `QldbClientBuilder::retry_credential_errors`.
+#[derive(Debug, Clone, Default)]
+pub struct DefaultRetryClassifier;
+
+impl RetryClassifier for DefaultRetryClassifier {
+    fn is_recoverable(&self, err: &RusotoError<SendCommandError>) -> bool {
+        !matches!(err, RusotoError::Credentials(_))
+    }
+}
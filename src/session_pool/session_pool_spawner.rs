@@ -1,99 +1,541 @@
+use crate::client_builder::{validate_ledger_name, validate_max_sessions};
+use crate::session_pool::DynQldbSession;
 use crate::session_pool::{
-    agnostic_async_pool_multithread::{receiver_task, returning_task},
-    Session, SessionPool, SpawnerFnMonoMultithread,
+    agnostic_async_pool_multithread::{
+        command_task, creator_task, keep_alive_task, receiver_task, returning_task, sweep_task,
+    },
+    agnostic_async_pool_shared::{
+        get_many_sessions, is_foreign_session, is_invalid_session_error, log_dropped_session, pool_stopped_error,
+        qldb_keep_alive_session, receive_session_with_timeout, request_session_creation, send_request_with_timeout,
+        wait_for_warming,
+    },
+    next_pool_id, CloseOnDrop, LastCreationError, PoolCommand, PoolConfig, PoolEvent, PoolEvents, PoolStats,
+    PooledSession, Priority, Session, SessionPool, SessionPoolError, SessionSender, SpawnerFnMonoMultithread, Warming,
+    MAX_INVALID_SESSION_RETRIES,
 };
-use async_channel::{bounded, unbounded, Sender};
-use eyre::WrapErr;
+use crate::QldbResult;
+use async_channel::{bounded, unbounded, Receiver, Sender};
+use futures::channel::oneshot;
 use rusoto_qldb_session::QldbSessionClient;
 use std::collections::VecDeque;
-use std::sync::atomic::{AtomicBool, AtomicU16, Ordering::Relaxed};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering::Relaxed};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct SpawnerSessionPool {
-    sender_request: Sender<Sender<Session>>,
+    sender_request: Sender<SessionSender>,
     sender_return: Sender<Session>,
+    sender_command: Sender<PoolCommand>,
     is_closed: Arc<AtomicBool>,
+    closing_receiver: Receiver<()>,
+    qldb_client: Arc<DynQldbSession>,
+    pool_id: u64,
+    events: Arc<PoolEvents>,
+    last_creation_error: Arc<LastCreationError>,
+    warming: Arc<Warming>,
+    _close_on_drop: Arc<CloseOnDrop>,
 }
 
 impl SpawnerSessionPool {
+    /// Creates the pool with the default configuration. The client
+    /// builder, `QldbClientBuilder::build_with_spawner`, is the one that
+    /// configures it. It fails for the same ledger names and `max_sessions`
+    /// as `build_with_spawner`.
     pub fn new(
         qldb_client: Arc<QldbSessionClient>,
         ledger_name: &str,
         max_sessions: u16,
         spawner: SpawnerFnMonoMultithread,
+    ) -> QldbResult<SpawnerSessionPool> {
+        validate_ledger_name(ledger_name)?;
+        validate_max_sessions(max_sessions)?;
+
+        Ok(SpawnerSessionPool::with_config(
+            qldb_client,
+            ledger_name,
+            max_sessions,
+            PoolConfig::default(),
+            spawner,
+        ))
+    }
+
+    pub(crate) fn with_config(
+        qldb_client: Arc<DynQldbSession>,
+        ledger_name: &str,
+        max_sessions: u16,
+        config: PoolConfig,
+        spawner: SpawnerFnMonoMultithread,
     ) -> SpawnerSessionPool {
-        let (requesting_sender, requesting_receiver) = unbounded::<Sender<Session>>();
+        let config = config.for_new_pool();
+        let events = config.events.clone();
+        let last_creation_error = config.last_creation_error.clone();
+        let warming = config.warming.clone();
+
+        let (requesting_sender, requesting_receiver) = match config.request_queue_capacity {
+            Some(capacity) => bounded::<SessionSender>(capacity),
+            None => unbounded::<SessionSender>(),
+        };
         let (returning_sender, returning_receiver) = unbounded::<Session>();
+        let (creating_sender, creating_receiver) = unbounded::<()>();
+        let (command_sender, command_receiver) = bounded::<PoolCommand>(config.command_queue_capacity);
+        let (closing_sender, closing_receiver) = bounded::<()>(1);
         let ledger_name = ledger_name.to_owned();
 
         let is_closed = Arc::new(AtomicBool::from(false));
-
-        let is_closed_return = is_closed.clone();
-        let requesting_sender_return = requesting_sender.clone();
+        let pool_id = next_pool_id();
 
         let sessions = Arc::new(Mutex::new(VecDeque::<Session>::with_capacity(max_sessions.into())));
+        let waiters = Arc::new(Mutex::new(VecDeque::<SessionSender>::new()));
         let session_count = Arc::new(AtomicU16::new(0));
+        let max_sessions = Arc::new(AtomicU16::new(max_sessions));
+        // Lowered while QLDB refuses to open more sessions
+        let server_limit = Arc::new(AtomicU16::new(u16::MAX));
+        // Sessions created over the max, for the stats
+        let overflow_created = Arc::new(AtomicU64::new(0));
 
         receiver_task(
             spawner.clone(),
-            max_sessions,
-            &ledger_name,
+            &config,
             &sessions,
+            &waiters,
             &session_count,
             &qldb_client,
             &is_closed,
             requesting_receiver,
-            requesting_sender,
+            creating_sender.clone(),
         );
 
         returning_task(
-            spawner,
+            spawner.clone(),
+            &config,
             &sessions,
+            &waiters,
             &session_count,
+            &server_limit,
             &qldb_client,
             &is_closed,
             returning_receiver,
+            creating_sender.clone(),
+        );
+
+        for _ in 0..config.min_idle {
+            request_session_creation(&creating_sender);
+        }
+
+        command_task(
+            spawner.clone(),
+            &config,
+            &sessions,
+            &waiters,
+            &max_sessions,
+            &session_count,
+            &overflow_created,
+            &qldb_client,
+            &is_closed,
+            command_receiver,
+            creating_sender.clone(),
+            closing_sender,
+        );
+
+        if let Some(interval) = config.keep_alive_interval {
+            keep_alive_task(
+                spawner.clone(),
+                interval,
+                &config,
+                &sessions,
+                &session_count,
+                &qldb_client,
+                &is_closed,
+                creating_sender.clone(),
+            );
+        }
+
+        sweep_task(
+            spawner.clone(),
+            config.sweep_interval,
+            &config,
+            &sessions,
+            &session_count,
+            &qldb_client,
+            &is_closed,
+            creating_sender.clone(),
+        );
+
+        creator_task(
+            spawner,
+            &max_sessions,
+            &ledger_name,
+            pool_id,
+            &config,
+            &sessions,
+            &waiters,
+            &session_count,
+            &server_limit,
+            &overflow_created,
+            &qldb_client,
+            &is_closed,
+            creating_receiver,
+            creating_sender,
         );
 
         SpawnerSessionPool {
-            sender_request: requesting_sender_return,
+            sender_request: requesting_sender,
             sender_return: returning_sender,
-            is_closed: is_closed_return,
+            sender_command: command_sender.clone(),
+            is_closed,
+            closing_receiver,
+            qldb_client,
+            pool_id,
+            events,
+            last_creation_error,
+            warming,
+            _close_on_drop: Arc::new(CloseOnDrop(command_sender)),
+        }
+    }
+
+    pub async fn close(&self) -> eyre::Result<()> {
+        let (sender, receiver) = bounded::<u16>(1);
+
+        self.sender_command
+            .send(PoolCommand::Close(sender))
+            .await
+            .map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        let still_open = receiver.recv().await.map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        if still_open > 0 {
+            return Err(eyre::eyre!(
+                "Session pool closed but {} sessions were still in use",
+                still_open
+            ));
         }
+
+        Ok(())
     }
 
-    pub async fn close(&self) {
-        self.is_closed.store(true, Relaxed);
+    pub async fn closed(&self) {
+        // Nothing is ever sent, the channel is closed once the pool starts
+        // closing or when its tasks stop
+        let _ = self.closing_receiver.recv().await;
     }
 
     pub async fn get(&self) -> eyre::Result<Session> {
-        let (sender, receiver) = bounded::<Session>(1);
+        self.get_with_priority(Priority::Normal).await
+    }
+
+    pub async fn get_with_priority(&self, priority: Priority) -> eyre::Result<Session> {
+        if self.is_closed.load(Relaxed) {
+            return Err(SessionPoolError::Closed.into());
+        }
 
-        self.sender_request.try_send(sender).wrap_err("Session pool closed")?;
+        // Every request gets its own reply channel. Reusing them isn't worth
+        // it: the channels of the requests that timed out are closed, so they
+        // cannot be reused, and creating one is a single allocation.
+        let (sender, receiver) = oneshot::channel::<Session>();
 
-        let session = receiver.recv().await.wrap_err("Session pool closed")?;
+        self.sender_request
+            .send(SessionSender::new(sender, priority, self.events.clone()))
+            .await
+            .map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        let session = receiver.await.map_err(|_| pool_stopped_error(&self.is_closed))?;
 
         Ok(session)
     }
 
+    pub async fn get_guard(&self) -> eyre::Result<PooledSession> {
+        let session = self.get().await?;
+
+        Ok(PooledSession::new(session, Arc::new(self.clone())))
+    }
+
+    pub async fn get_many(&self, n: usize) -> eyre::Result<Vec<Session>> {
+        get_many_sessions(n, || self.get(), |session| self.give_back(session)).await
+    }
+
+    pub async fn get_retrying(&self) -> eyre::Result<Session> {
+        let mut tries: u32 = 0;
+
+        loop {
+            tries = tries.saturating_add(1);
+
+            let session = self.get().await?;
+
+            match qldb_keep_alive_session(&self.qldb_client, &session).await {
+                Ok(_) => return Ok(session.refreshed()),
+                Err(err) if is_invalid_session_error(&err) => {
+                    self.discard(session);
+
+                    if tries >= MAX_INVALID_SESSION_RETRIES {
+                        return Err(eyre::eyre!(err).wrap_err("QLDB keeps returning invalid sessions"));
+                    }
+                }
+                Err(err) => {
+                    self.give_back(session);
+                    return Err(eyre::eyre!(err).wrap_err("Cannot check if the session is still valid"));
+                }
+            }
+        }
+    }
+
+    pub async fn get_with_timeout(&self, timeout: Duration) -> eyre::Result<Option<Session>> {
+        if self.is_closed.load(Relaxed) {
+            return Err(SessionPoolError::Closed.into());
+        }
+
+        let started_on = Instant::now();
+
+        let (sender, receiver) = oneshot::channel::<Session>();
+        let sender = SessionSender::new(sender, Priority::Normal, self.events.clone());
+
+        if !send_request_with_timeout(&self.sender_request, sender, &self.is_closed, timeout).await? {
+            return Ok(None);
+        }
+
+        receive_session_with_timeout(
+            receiver,
+            &self.sender_return,
+            &self.is_closed,
+            timeout.saturating_sub(started_on.elapsed()),
+        )
+        .await
+    }
+
+    pub async fn try_get(&self) -> eyre::Result<Option<Session>> {
+        if self.is_closed.load(Relaxed) {
+            return Err(SessionPoolError::Closed.into());
+        }
+
+        let (sender, receiver) = bounded::<Option<Session>>(1);
+
+        self.sender_command
+            .send(PoolCommand::TryRequest(sender))
+            .await
+            .map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        let session = receiver.recv().await.map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        Ok(session)
+    }
+
+    pub async fn pending_requests(&self) -> eyre::Result<usize> {
+        let stats = self.stats().await?;
+
+        // Requests still in the channel haven't been seen by the pool yet
+        Ok(stats.waiters + self.sender_request.len())
+    }
+
+    pub async fn set_max_sessions(&self, new_max: u16) -> eyre::Result<()> {
+        // With no sessions every waiter would hang forever
+        validate_max_sessions(new_max)?;
+
+        self.sender_command
+            .send(PoolCommand::SetMax(new_max))
+            .await
+            .map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        Ok(())
+    }
+
+    pub async fn warm(&self, target: u16, timeout: Duration) -> eyre::Result<bool> {
+        if self.is_closed.load(Relaxed) {
+            return Err(SessionPoolError::Closed.into());
+        }
+
+        let started_on = Instant::now();
+
+        let (sender, receiver) = bounded::<()>(1);
+
+        self.sender_command
+            .send(PoolCommand::Warm(target, sender))
+            .await
+            .map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        receiver.recv().await.map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        let warmed = wait_for_warming(&self.warming, timeout.saturating_sub(started_on.elapsed())).await;
+
+        // Closing the pool stops the warm, as nothing is created anymore
+        if self.is_closed.load(Relaxed) {
+            return Err(SessionPoolError::Closed.into());
+        }
+
+        Ok(warmed)
+    }
+
+    pub async fn stats(&self) -> eyre::Result<PoolStats> {
+        let (sender, receiver) = bounded::<PoolStats>(1);
+
+        self.sender_command
+            .send(PoolCommand::Stats(sender))
+            .await
+            .map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        let stats = receiver.recv().await.map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        Ok(stats)
+    }
+
+    pub fn last_creation_error(&self) -> Option<String> {
+        self.last_creation_error.get()
+    }
+
+    pub async fn idle_session_ids(&self) -> eyre::Result<Vec<String>> {
+        let (sender, receiver) = bounded::<Vec<String>>(1);
+
+        self.sender_command
+            .send(PoolCommand::IdleSessionIds(sender))
+            .await
+            .map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        let session_ids = receiver.recv().await.map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        Ok(session_ids)
+    }
+
+    pub async fn drain_idle(&self) -> eyre::Result<usize> {
+        let (sender, receiver) = bounded::<usize>(1);
+
+        self.sender_command
+            .send(PoolCommand::DrainIdle(sender))
+            .await
+            .map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        let drained = receiver.recv().await.map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        Ok(drained)
+    }
+
+    pub fn subscribe(&self) -> Receiver<PoolEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn discard(&self, session: Session) {
+        // Once expired the returning task closes it instead of queuing it
+        // again. It keeps doing so while and after the pool closes, unlike
+        // the command task, which is busy waiting for the sessions then.
+        self.give_back(session.invalidated());
+    }
+
+    pub async fn give_back_checked(&self, session: Session) -> eyre::Result<()> {
+        if is_foreign_session(&session, self.pool_id) {
+            return Err(eyre::eyre!("The session doesn't belong to this session pool"));
+        }
+
+        self.sender_return
+            .send(session)
+            .await
+            .map_err(|_| pool_stopped_error(&self.is_closed))?;
+
+        Ok(())
+    }
+
     pub fn give_back(&self, session: Session) {
-        // TODO: We maybe shouldn't be ignoring this error
-        let _ = self.sender_return.try_send(session);
+        if is_foreign_session(&session, self.pool_id) {
+            return;
+        }
+
+        if let Err(err) = self.sender_return.try_send(session) {
+            log_dropped_session(err, &self.is_closed, || {
+                format!("{:?}", eyre::Report::from(pool_stopped_error(&self.is_closed)))
+            });
+        }
+    }
+}
+
+impl fmt::Debug for SpawnerSessionPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpawnerSessionPool")
+            .field("sender_request", &self.sender_request)
+            .field("sender_return", &self.sender_return)
+            .field("sender_command", &self.sender_command)
+            .field("is_closed", &self.is_closed)
+            .field("pool_id", &self.pool_id)
+            .finish()
     }
 }
 
 #[async_trait::async_trait]
 impl SessionPool for SpawnerSessionPool {
-    async fn close(&self) {
+    async fn close(&self) -> eyre::Result<()> {
         self.close().await
     }
 
+    async fn closed(&self) {
+        self.closed().await
+    }
+
     async fn get(&self) -> eyre::Result<Session> {
         self.get().await
     }
 
+    async fn get_guard(&self) -> eyre::Result<PooledSession> {
+        self.get_guard().await
+    }
+
+    async fn get_with_priority(&self, priority: Priority) -> eyre::Result<Session> {
+        self.get_with_priority(priority).await
+    }
+
+    async fn get_many(&self, n: usize) -> eyre::Result<Vec<Session>> {
+        self.get_many(n).await
+    }
+
+    async fn get_retrying(&self) -> eyre::Result<Session> {
+        self.get_retrying().await
+    }
+
+    async fn get_with_timeout(&self, timeout: Duration) -> eyre::Result<Option<Session>> {
+        self.get_with_timeout(timeout).await
+    }
+
+    async fn try_get(&self) -> eyre::Result<Option<Session>> {
+        self.try_get().await
+    }
+
+    async fn stats(&self) -> eyre::Result<PoolStats> {
+        self.stats().await
+    }
+
+    fn last_creation_error(&self) -> Option<String> {
+        self.last_creation_error()
+    }
+
+    async fn idle_session_ids(&self) -> eyre::Result<Vec<String>> {
+        self.idle_session_ids().await
+    }
+
+    fn subscribe(&self) -> Receiver<PoolEvent> {
+        self.subscribe()
+    }
+
+    async fn drain_idle(&self) -> eyre::Result<usize> {
+        self.drain_idle().await
+    }
+
+    async fn pending_requests(&self) -> eyre::Result<usize> {
+        self.pending_requests().await
+    }
+
+    async fn set_max_sessions(&self, new_max: u16) -> eyre::Result<()> {
+        self.set_max_sessions(new_max).await
+    }
+
+    async fn warm(&self, target: u16, timeout: Duration) -> eyre::Result<bool> {
+        self.warm(target, timeout).await
+    }
+
+    async fn give_back_checked(&self, session: Session) -> eyre::Result<()> {
+        self.give_back_checked(session).await
+    }
+
     fn give_back(&self, session: Session) {
         self.give_back(session)
     }
+
+    fn discard(&self, session: Session) {
+        self.discard(session)
+    }
 }
@@ -1,104 +1,667 @@
+use crate::client_builder::{validate_ledger_name, validate_max_sessions};
+use crate::session_pool::DynQldbSession;
 use crate::session_pool::{
-    agnostic_async_pool_monothread::{receiver_task, returning_task},
-    Session, SessionPool,
+    agnostic_async_pool_monothread::{
+        command_task, creator_task, keep_alive_task, receiver_task, returning_task, sweep_task,
+    },
+    agnostic_async_pool_shared::{
+        get_many_sessions, is_foreign_session, is_invalid_session_error, log_dropped_session, panic_payload_message,
+        pool_stopped_error, qldb_keep_alive_session, receive_session_with_timeout, request_session_creation,
+        send_request_with_timeout, wait_for_warming,
+    },
+    next_pool_id, CloseOnDrop, LastCreationError, PoolCommand, PoolConfig, PoolEvent, PoolEvents, PoolStats,
+    PooledSession, Priority, Session, SessionPool, SessionPoolError, SessionSender, SpawnerFnMonothread, Warming,
+    MAX_INVALID_SESSION_RETRIES,
 };
-use async_channel::{bounded, unbounded, Sender};
+use crate::QldbResult;
+use async_channel::{bounded, unbounded, Receiver, Sender};
 use async_executor::LocalExecutor;
-use eyre::WrapErr;
+use futures::channel::oneshot;
+use log::error;
 use rusoto_qldb_session::QldbSessionClient;
 use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
 use std::rc::Rc;
-use std::sync::atomic::{AtomicBool, AtomicU16, Ordering::Relaxed};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct ThreadedSessionPool {
-    sender_request: Sender<Sender<Session>>,
+    sender_request: Sender<SessionSender>,
     sender_return: Sender<Session>,
+    sender_command: Sender<PoolCommand>,
     is_closed: Arc<AtomicBool>,
+    closing_receiver: Receiver<()>,
+    qldb_client: Arc<DynQldbSession>,
+    pool_id: u64,
+    events: Arc<PoolEvents>,
+    last_creation_error: Arc<LastCreationError>,
+    warming: Arc<Warming>,
+    _close_on_drop: Arc<CloseOnDrop>,
+    pool_thread: PoolThread,
+}
+
+/// Starts the tasks of a session pool once it is running in the thread.
+/// The future it returns completes once the pool is closed.
+type PoolStarter = Box<dyn FnOnce(SpawnerFnMonothread) -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+
+/// The thread where the tasks of one or more session pools run, all of
+/// them on the same executor.
+#[derive(Clone)]
+pub(crate) struct PoolThread {
+    sender_start: Sender<PoolStarter>,
+    thread: Arc<JoinHandle<()>>,
+    // Filled if the pool thread panics, so the error can be reported
+    panic_message: Arc<Mutex<Option<String>>>,
+}
+
+impl PoolThread {
+    pub(crate) fn spawn() -> PoolThread {
+        let (starting_sender, starting_receiver) = unbounded::<PoolStarter>();
+
+        let panic_message = Arc::new(Mutex::new(None::<String>));
+        let panic_message_thread = panic_message.clone();
+
+        let thread = std::thread::spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(move || {
+                let executor = Rc::new(LocalExecutor::new());
+                let executor_spawn = executor.clone();
+                // `SpawnerFnMonothread` is an `Arc` for compatibility, though
+                // it never leaves this thread
+                #[allow(clippy::arc_with_non_send_sync)]
+                let spawner: SpawnerFnMonothread = Arc::new(move |fut| executor_spawn.spawn(Box::pin(fut)).detach());
+
+                // Once nothing can start pools anymore, i.e. the clients and
+                // their pools are gone, the thread stops with their tasks,
+                // after the pools finish closing their sessions
+                futures::executor::block_on(executor.run(async move {
+                    let mut pools_closed = Vec::new();
+
+                    while let Ok(start) = starting_receiver.recv().await {
+                        pools_closed.push(start(spawner.clone()));
+                    }
+
+                    futures::future::join_all(pools_closed).await;
+                }));
+            }));
+
+            if let Err(payload) = result {
+                let message = panic_payload_message(payload.as_ref());
+
+                error!(
+                    "QLDB driver internal error. The session pool thread panicked: {}",
+                    message
+                );
+
+                if let Ok(mut panic_message) = panic_message_thread.lock() {
+                    *panic_message = Some(message);
+                }
+            }
+        });
+
+        PoolThread {
+            sender_start: starting_sender,
+            thread: Arc::new(thread),
+            panic_message,
+        }
+    }
+
+    /// Adds the reason why the thread died, if it did, to an error
+    /// returned by one of its pools.
+    fn explain_error(&self, err: eyre::Report) -> eyre::Report {
+        // The thread only finishes on its own once nobody holds it, so
+        // while we are here it is running unless it panicked
+        if !self.thread.is_finished() {
+            return err;
+        }
+
+        match self.panic_message.lock().ok().and_then(|message| message.clone()) {
+            Some(message) => err.wrap_err(format!("The session pool thread panicked: {}", message)),
+            None => err.wrap_err("The session pool thread stopped"),
+        }
+    }
+}
+
+impl fmt::Debug for PoolThread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolThread").field("thread", &self.thread).finish()
+    }
 }
 
 impl ThreadedSessionPool {
-    pub fn new(qldb_client: Arc<QldbSessionClient>, ledger_name: &str, max_sessions: u16) -> ThreadedSessionPool {
-        let (requesting_sender, requesting_receiver) = unbounded::<Sender<Session>>();
+    /// Creates the pool, in its own thread, with the default configuration.
+    /// The client builder, `QldbClientBuilder::build`, is the one that
+    /// configures it. It fails for the same ledger names and `max_sessions`
+    /// as `build`.
+    pub fn new(
+        qldb_client: Arc<QldbSessionClient>,
+        ledger_name: &str,
+        max_sessions: u16,
+    ) -> QldbResult<ThreadedSessionPool> {
+        validate_ledger_name(ledger_name)?;
+        validate_max_sessions(max_sessions)?;
+
+        Ok(ThreadedSessionPool::with_config(
+            &PoolThread::spawn(),
+            qldb_client,
+            ledger_name,
+            max_sessions,
+            PoolConfig::default(),
+        ))
+    }
+
+    /// Creates the pool in a thread that may be already running other pools.
+    pub(crate) fn with_config(
+        pool_thread: &PoolThread,
+        qldb_client: Arc<DynQldbSession>,
+        ledger_name: &str,
+        max_sessions: u16,
+        config: PoolConfig,
+    ) -> ThreadedSessionPool {
+        let config = config.for_new_pool();
+        let events = config.events.clone();
+        let last_creation_error = config.last_creation_error.clone();
+        let warming = config.warming.clone();
+
+        let (requesting_sender, requesting_receiver) = match config.request_queue_capacity {
+            Some(capacity) => bounded::<SessionSender>(capacity),
+            None => unbounded::<SessionSender>(),
+        };
         let (returning_sender, returning_receiver) = unbounded::<Session>();
+        let (creating_sender, creating_receiver) = unbounded::<()>();
+        let (command_sender, command_receiver) = bounded::<PoolCommand>(config.command_queue_capacity);
+        let (closing_sender, closing_receiver) = bounded::<()>(1);
+        // Closed once the pool finished closing, so the thread can stop
+        let (closed_sender, closed_receiver) = bounded::<()>(1);
         let ledger_name = ledger_name.to_owned();
 
         let is_closed = Arc::new(AtomicBool::from(false));
+        let pool_id = next_pool_id();
 
         let is_closed_return = is_closed.clone();
-        let requesting_sender_return = requesting_sender.clone();
-
-        std::thread::spawn(move || {
-            let executor = Arc::new(LocalExecutor::new());
-            let executor2 = executor.clone();
-            let executor3 = executor.clone();
-            let sessions = Rc::new(RefCell::new(VecDeque::<Session>::with_capacity(max_sessions.into())));
-            let session_count = Rc::new(AtomicU16::new(0));
-
-            receiver_task(
-                Arc::new(move |fut| executor.spawn(Box::pin(fut)).detach()),
-                max_sessions,
-                &ledger_name,
-                &sessions,
-                &session_count,
-                &qldb_client,
-                &is_closed,
-                requesting_receiver,
-                requesting_sender,
-            );
-
-            returning_task(
-                Arc::new(move |fut| executor2.spawn(Box::pin(fut)).detach()),
-                &sessions,
-                &session_count,
-                &qldb_client,
-                &is_closed,
-                returning_receiver,
-            );
-
-            futures::executor::block_on(executor3.run(futures::future::pending::<()>()));
-        });
+        let qldb_client_return = qldb_client.clone();
+
+        for _ in 0..config.min_idle {
+            request_session_creation(&creating_sender);
+        }
+
+        // If the thread is dead the channels are closed with the starter,
+        // so the pool methods will report it
+        let _ = pool_thread.sender_start.try_send(Box::new(
+            move |spawner: SpawnerFnMonothread| -> Pin<Box<dyn Future<Output = ()>>> {
+                let sessions = Rc::new(RefCell::new(VecDeque::<Session>::with_capacity(max_sessions.into())));
+                let waiters = Rc::new(RefCell::new(VecDeque::<SessionSender>::new()));
+                let session_count = Rc::new(AtomicU16::new(0));
+                let max_sessions = Rc::new(AtomicU16::new(max_sessions));
+                // Lowered while QLDB refuses to open more sessions
+                let server_limit = Rc::new(AtomicU16::new(u16::MAX));
+                // Sessions created over the max, for the stats
+                let overflow_created = Rc::new(AtomicU64::new(0));
+
+                receiver_task(
+                    spawner.clone(),
+                    &config,
+                    &sessions,
+                    &waiters,
+                    &session_count,
+                    &qldb_client,
+                    &is_closed,
+                    requesting_receiver,
+                    creating_sender.clone(),
+                );
+
+                returning_task(
+                    spawner.clone(),
+                    &config,
+                    &sessions,
+                    &waiters,
+                    &session_count,
+                    &server_limit,
+                    &qldb_client,
+                    &is_closed,
+                    returning_receiver,
+                    creating_sender.clone(),
+                );
+
+                command_task(
+                    spawner.clone(),
+                    &config,
+                    &sessions,
+                    &waiters,
+                    &max_sessions,
+                    &session_count,
+                    &overflow_created,
+                    &qldb_client,
+                    &is_closed,
+                    command_receiver,
+                    creating_sender.clone(),
+                    closing_sender,
+                    closed_sender,
+                );
+
+                if let Some(interval) = config.keep_alive_interval {
+                    keep_alive_task(
+                        spawner.clone(),
+                        interval,
+                        &config,
+                        &sessions,
+                        &session_count,
+                        &qldb_client,
+                        &is_closed,
+                        creating_sender.clone(),
+                    );
+                }
+
+                sweep_task(
+                    spawner.clone(),
+                    config.sweep_interval,
+                    &config,
+                    &sessions,
+                    &session_count,
+                    &qldb_client,
+                    &is_closed,
+                    creating_sender.clone(),
+                );
+
+                creator_task(
+                    spawner,
+                    &max_sessions,
+                    &ledger_name,
+                    pool_id,
+                    &config,
+                    &sessions,
+                    &waiters,
+                    &session_count,
+                    &server_limit,
+                    &overflow_created,
+                    &qldb_client,
+                    &is_closed,
+                    creating_receiver,
+                    creating_sender,
+                );
+
+                Box::pin(async move {
+                    let _ = closed_receiver.recv().await;
+                })
+            },
+        ));
 
         ThreadedSessionPool {
-            sender_request: requesting_sender_return,
+            sender_request: requesting_sender,
             sender_return: returning_sender,
+            sender_command: command_sender.clone(),
             is_closed: is_closed_return,
+            closing_receiver,
+            qldb_client: qldb_client_return,
+            pool_id,
+            events,
+            last_creation_error,
+            warming,
+            _close_on_drop: Arc::new(CloseOnDrop(command_sender.clone())),
+            pool_thread: pool_thread.clone(),
+        }
+    }
+
+    fn explain_error(&self, err: eyre::Report) -> eyre::Report {
+        self.pool_thread.explain_error(err)
+    }
+
+    fn stopped_error(&self) -> eyre::Report {
+        self.explain_error(pool_stopped_error(&self.is_closed).into())
+    }
+
+    pub async fn close(&self) -> eyre::Result<()> {
+        let (sender, receiver) = bounded::<u16>(1);
+
+        self.sender_command
+            .send(PoolCommand::Close(sender))
+            .await
+            .map_err(|_| self.stopped_error())?;
+
+        let still_open = receiver.recv().await.map_err(|_| self.stopped_error())?;
+
+        if still_open > 0 {
+            return Err(eyre::eyre!(
+                "Session pool closed but {} sessions were still in use",
+                still_open
+            ));
         }
+
+        Ok(())
     }
 
-    pub async fn close(&self) {
-        self.is_closed.store(true, Relaxed);
+    pub async fn closed(&self) {
+        // Nothing is ever sent, the channel is closed once the pool starts
+        // closing or when its tasks stop
+        let _ = self.closing_receiver.recv().await;
     }
 
     pub async fn get(&self) -> eyre::Result<Session> {
-        let (sender, receiver) = bounded::<Session>(1);
+        self.get_with_priority(Priority::Normal).await
+    }
+
+    pub async fn get_with_priority(&self, priority: Priority) -> eyre::Result<Session> {
+        if self.is_closed.load(Relaxed) {
+            return Err(SessionPoolError::Closed.into());
+        }
+
+        // Every request gets its own reply channel. Reusing them isn't worth
+        // it: the channels of the requests that timed out are closed, so they
+        // cannot be reused, and creating one is a single allocation.
+        let (sender, receiver) = oneshot::channel::<Session>();
+
+        self.sender_request
+            .send(SessionSender::new(sender, priority, self.events.clone()))
+            .await
+            .map_err(|_| self.stopped_error())?;
+
+        let session = receiver.await.map_err(|_| self.stopped_error())?;
+
+        Ok(session)
+    }
+
+    pub async fn get_guard(&self) -> eyre::Result<PooledSession> {
+        let session = self.get().await?;
+
+        Ok(PooledSession::new(session, Arc::new(self.clone())))
+    }
 
-        self.sender_request.try_send(sender).wrap_err("Session pool closed")?;
+    pub async fn get_many(&self, n: usize) -> eyre::Result<Vec<Session>> {
+        get_many_sessions(n, || self.get(), |session| self.give_back(session)).await
+    }
+
+    pub async fn get_retrying(&self) -> eyre::Result<Session> {
+        let mut tries: u32 = 0;
+
+        loop {
+            tries = tries.saturating_add(1);
+
+            let session = self.get().await?;
+
+            match qldb_keep_alive_session(&self.qldb_client, &session).await {
+                Ok(_) => return Ok(session.refreshed()),
+                Err(err) if is_invalid_session_error(&err) => {
+                    self.discard(session);
+
+                    if tries >= MAX_INVALID_SESSION_RETRIES {
+                        return Err(eyre::eyre!(err).wrap_err("QLDB keeps returning invalid sessions"));
+                    }
+                }
+                Err(err) => {
+                    self.give_back(session);
+                    return Err(eyre::eyre!(err).wrap_err("Cannot check if the session is still valid"));
+                }
+            }
+        }
+    }
+
+    pub async fn get_with_timeout(&self, timeout: Duration) -> eyre::Result<Option<Session>> {
+        if self.is_closed.load(Relaxed) {
+            return Err(SessionPoolError::Closed.into());
+        }
+
+        let started_on = Instant::now();
 
-        let session = receiver.recv().await.wrap_err("Session pool closed")?;
+        let (sender, receiver) = oneshot::channel::<Session>();
+        let sender = SessionSender::new(sender, Priority::Normal, self.events.clone());
+
+        if !send_request_with_timeout(&self.sender_request, sender, &self.is_closed, timeout)
+            .await
+            .map_err(|err| self.explain_error(err))?
+        {
+            return Ok(None);
+        }
+
+        receive_session_with_timeout(
+            receiver,
+            &self.sender_return,
+            &self.is_closed,
+            timeout.saturating_sub(started_on.elapsed()),
+        )
+        .await
+        .map_err(|err| self.explain_error(err))
+    }
+
+    pub async fn try_get(&self) -> eyre::Result<Option<Session>> {
+        if self.is_closed.load(Relaxed) {
+            return Err(SessionPoolError::Closed.into());
+        }
+
+        let (sender, receiver) = bounded::<Option<Session>>(1);
+
+        self.sender_command
+            .send(PoolCommand::TryRequest(sender))
+            .await
+            .map_err(|_| self.stopped_error())?;
+
+        let session = receiver.recv().await.map_err(|_| self.stopped_error())?;
 
         Ok(session)
     }
 
+    pub async fn pending_requests(&self) -> eyre::Result<usize> {
+        let stats = self.stats().await?;
+
+        // Requests still in the channel haven't been seen by the pool yet
+        Ok(stats.waiters + self.sender_request.len())
+    }
+
+    pub async fn set_max_sessions(&self, new_max: u16) -> eyre::Result<()> {
+        // With no sessions every waiter would hang forever
+        validate_max_sessions(new_max)?;
+
+        self.sender_command
+            .send(PoolCommand::SetMax(new_max))
+            .await
+            .map_err(|_| self.stopped_error())?;
+
+        Ok(())
+    }
+
+    pub async fn warm(&self, target: u16, timeout: Duration) -> eyre::Result<bool> {
+        if self.is_closed.load(Relaxed) {
+            return Err(SessionPoolError::Closed.into());
+        }
+
+        let started_on = Instant::now();
+
+        let (sender, receiver) = bounded::<()>(1);
+
+        self.sender_command
+            .send(PoolCommand::Warm(target, sender))
+            .await
+            .map_err(|_| self.stopped_error())?;
+
+        receiver.recv().await.map_err(|_| self.stopped_error())?;
+
+        let warmed = wait_for_warming(&self.warming, timeout.saturating_sub(started_on.elapsed())).await;
+
+        // Closing the pool stops the warm, as nothing is created anymore
+        if self.is_closed.load(Relaxed) {
+            return Err(SessionPoolError::Closed.into());
+        }
+
+        Ok(warmed)
+    }
+
+    pub async fn stats(&self) -> eyre::Result<PoolStats> {
+        let (sender, receiver) = bounded::<PoolStats>(1);
+
+        self.sender_command
+            .send(PoolCommand::Stats(sender))
+            .await
+            .map_err(|_| self.stopped_error())?;
+
+        let stats = receiver.recv().await.map_err(|_| self.stopped_error())?;
+
+        Ok(stats)
+    }
+
+    pub fn last_creation_error(&self) -> Option<String> {
+        self.last_creation_error.get()
+    }
+
+    pub async fn idle_session_ids(&self) -> eyre::Result<Vec<String>> {
+        let (sender, receiver) = bounded::<Vec<String>>(1);
+
+        self.sender_command
+            .send(PoolCommand::IdleSessionIds(sender))
+            .await
+            .map_err(|_| self.stopped_error())?;
+
+        let session_ids = receiver.recv().await.map_err(|_| self.stopped_error())?;
+
+        Ok(session_ids)
+    }
+
+    pub async fn drain_idle(&self) -> eyre::Result<usize> {
+        let (sender, receiver) = bounded::<usize>(1);
+
+        self.sender_command
+            .send(PoolCommand::DrainIdle(sender))
+            .await
+            .map_err(|_| self.stopped_error())?;
+
+        let drained = receiver.recv().await.map_err(|_| self.stopped_error())?;
+
+        Ok(drained)
+    }
+
+    pub fn subscribe(&self) -> Receiver<PoolEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn discard(&self, session: Session) {
+        // Once expired the returning task closes it instead of queuing it
+        // again. It keeps doing so while and after the pool closes, unlike
+        // the command task, which is busy waiting for the sessions then.
+        self.give_back(session.invalidated());
+    }
+
+    pub async fn give_back_checked(&self, session: Session) -> eyre::Result<()> {
+        if is_foreign_session(&session, self.pool_id) {
+            return Err(eyre::eyre!("The session doesn't belong to this session pool"));
+        }
+
+        self.sender_return
+            .send(session)
+            .await
+            .map_err(|_| self.stopped_error())?;
+
+        Ok(())
+    }
+
     pub fn give_back(&self, session: Session) {
-        // TODO: We maybe shouldn't be ignoring this error
-        let _ = self.sender_return.try_send(session);
+        if is_foreign_session(&session, self.pool_id) {
+            return;
+        }
+
+        if let Err(err) = self.sender_return.try_send(session) {
+            log_dropped_session(err, &self.is_closed, || {
+                format!("{:?}", self.explain_error(eyre::eyre!("The session pool stopped")))
+            });
+        }
+    }
+}
+
+impl fmt::Debug for ThreadedSessionPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadedSessionPool")
+            .field("sender_request", &self.sender_request)
+            .field("sender_return", &self.sender_return)
+            .field("sender_command", &self.sender_command)
+            .field("is_closed", &self.is_closed)
+            .field("pool_id", &self.pool_id)
+            .field("pool_thread", &self.pool_thread)
+            .finish()
     }
 }
 
 #[async_trait::async_trait]
 impl SessionPool for ThreadedSessionPool {
-    async fn close(&self) {
+    async fn close(&self) -> eyre::Result<()> {
         self.close().await
     }
 
+    async fn closed(&self) {
+        self.closed().await
+    }
+
     async fn get(&self) -> eyre::Result<Session> {
         self.get().await
     }
 
+    async fn get_guard(&self) -> eyre::Result<PooledSession> {
+        self.get_guard().await
+    }
+
+    async fn get_with_priority(&self, priority: Priority) -> eyre::Result<Session> {
+        self.get_with_priority(priority).await
+    }
+
+    async fn get_many(&self, n: usize) -> eyre::Result<Vec<Session>> {
+        self.get_many(n).await
+    }
+
+    async fn get_retrying(&self) -> eyre::Result<Session> {
+        self.get_retrying().await
+    }
+
+    async fn get_with_timeout(&self, timeout: Duration) -> eyre::Result<Option<Session>> {
+        self.get_with_timeout(timeout).await
+    }
+
+    async fn try_get(&self) -> eyre::Result<Option<Session>> {
+        self.try_get().await
+    }
+
+    async fn stats(&self) -> eyre::Result<PoolStats> {
+        self.stats().await
+    }
+
+    fn last_creation_error(&self) -> Option<String> {
+        self.last_creation_error()
+    }
+
+    async fn idle_session_ids(&self) -> eyre::Result<Vec<String>> {
+        self.idle_session_ids().await
+    }
+
+    fn subscribe(&self) -> Receiver<PoolEvent> {
+        self.subscribe()
+    }
+
+    async fn drain_idle(&self) -> eyre::Result<usize> {
+        self.drain_idle().await
+    }
+
+    async fn pending_requests(&self) -> eyre::Result<usize> {
+        self.pending_requests().await
+    }
+
+    async fn set_max_sessions(&self, new_max: u16) -> eyre::Result<()> {
+        self.set_max_sessions(new_max).await
+    }
+
+    async fn warm(&self, target: u16, timeout: Duration) -> eyre::Result<bool> {
+        self.warm(target, timeout).await
+    }
+
+    async fn give_back_checked(&self, session: Session) -> eyre::Result<()> {
+        self.give_back_checked(session).await
+    }
+
     fn give_back(&self, session: Session) {
         self.give_back(session)
     }
+
+    fn discard(&self, session: Session) {
+        self.discard(session)
+    }
 }
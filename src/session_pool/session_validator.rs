@@ -0,0 +1,22 @@
+use crate::session_pool::Session;
+use std::fmt::Debug;
+
+/// Decides whether a session can still be used, e.g. to retire the sessions
+/// of a region sooner or after some event. It is checked on top of
+/// `session_ttl` and `max_session_uses`, every time the pool is about to
+/// hand out or keep a session, so it needs to be cheap. Calling
+/// `Session::is_valid` from it would never end.
+pub trait SessionValidator: Debug + Send + Sync {
+    fn is_valid(&self, session: &Session) -> bool;
+}
+
+/// The validator used by default. It accepts every session, so only the
+/// ttl and the max uses apply.
+#[derive(Debug, Clone, Default)]
+pub struct DefaultSessionValidator;
+
+impl SessionValidator for DefaultSessionValidator {
+    fn is_valid(&self, _session: &Session) -> bool {
+        true
+    }
+}
@@ -1,16 +1,18 @@
-use crate::session_pool::{Session, SessionPool};
+use crate::instrument::traced_command;
+use crate::session_pool::{DynQldbSession, PooledSession, Session, SessionPool};
 use crate::types::{QldbError, QldbResult};
-use crate::QueryBuilder;
+use crate::{DocumentCollection, QueryBuilder};
+use async_compat::CompatExt;
 use futures::lock::Mutex;
 use futures::lock::MutexGuard;
 use ion_binary_rs::{IonEncoder, IonHash, IonValue};
 use rusoto_qldb_session::{
-    AbortTransactionRequest, CommitTransactionRequest, QldbSession, QldbSessionClient, SendCommandRequest,
-    StartTransactionRequest,
+    AbortTransactionRequest, CommitTransactionRequest, SendCommandRequest, StartTransactionRequest,
 };
 use sha2::Sha256;
 use std::fmt::Debug;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug)]
 enum TransactionStatus {
@@ -21,25 +23,84 @@ enum TransactionStatus {
 
 /// Every query in QLDB is within a transaction. Ideally you will interact
 /// with this object via the method QLDBClient::transaction_within.
+///
+/// It owns the session taken from the pool until it is committed or
+/// rolled back, and it keeps the hash of every query so the commit
+/// digest can be sent to QLDB.
+///
+/// The clones of a transaction are the same transaction, so one task can
+/// start it and hand a clone to another task that completes it. The session
+/// goes back to the pool once any of them commits or rolls back.
+///
+/// ```rust,no_run
+/// use qldb::QldbClient;
+/// # use eyre::Result;
+///
+/// # async fn test() -> Result<()> {
+/// let client = QldbClient::default("rust-crate-test", 200).await?;
+///
+/// let transaction = client.transaction().await?;
+///
+/// transaction
+///     .query("INSERT INTO TestTable VALUE ?")
+///     .param("test_value")
+///     .execute()
+///     .await?;
+///
+/// transaction.commit().await?;
+/// # Ok(())
+/// # }
+/// ```
 #[derive(Clone)]
 pub struct Transaction {
-    client: Arc<QldbSessionClient>,
+    client: Arc<DynQldbSession>,
     session_pool: Arc<dyn SessionPool>,
     pub(crate) transaction_id: Arc<String>,
     pub(crate) session: Arc<Session>,
     completed: Arc<Mutex<TransactionStatus>>,
     hasher: Arc<Mutex<IonHash>>,
     auto_rollback: bool,
+    // The try of `QldbClient::transact` running it, as reported to the observer
+    pub(crate) attempt: u32,
 }
 
 impl Transaction {
     pub(crate) async fn new(
-        client: Arc<QldbSessionClient>,
+        client: Arc<DynQldbSession>,
         session_pool: Arc<dyn SessionPool>,
-        session: Session,
+        session: PooledSession,
         auto_rollback: bool,
     ) -> QldbResult<Transaction> {
-        let transaction_id = Transaction::get_transaction_id(&client, session.get_session_id()).await?;
+        Transaction::for_attempt(client, session_pool, session, auto_rollback, 1).await
+    }
+
+    pub(crate) async fn for_attempt(
+        client: Arc<DynQldbSession>,
+        session_pool: Arc<dyn SessionPool>,
+        session: PooledSession,
+        auto_rollback: bool,
+        attempt: u32,
+    ) -> QldbResult<Transaction> {
+        // If the transaction cannot be started the guard gives the session back
+        let (session, transaction_id) = match Transaction::get_transaction_id(&client, &session, attempt).await {
+            Ok(transaction_id) => (session.into_session(), transaction_id),
+            // QLDB expired the session before we did, so we replace it
+            // by one that QLDB confirms that is still alive
+            Err(err) if err.is_invalid_session() => {
+                session_pool.discard(session.into_session());
+
+                let session = session_pool
+                    .get_retrying()
+                    .await
+                    .map_err(QldbError::from_session_pool)?;
+                let session = PooledSession::new(session, session_pool.clone());
+
+                let transaction_id = Transaction::get_transaction_id(&client, &session, attempt).await?;
+
+                (session.into_session(), transaction_id)
+            }
+            Err(err) => return Err(err),
+        };
 
         let hasher = IonHash::from_ion_value::<Sha256>(&IonValue::String(transaction_id.clone()));
 
@@ -51,42 +112,107 @@ impl Transaction {
             completed: Arc::new(Mutex::new(TransactionStatus::Open)),
             hasher: Arc::new(Mutex::new(hasher)),
             auto_rollback,
+            attempt,
         })
     }
 
+    /// The ledger this transaction runs on. Useful for logs when using
+    /// several ledgers with `QldbClient::for_ledger`.
+    pub fn ledger_name(&self) -> &str {
+        self.session.get_ledger_name()
+    }
+
+    /// How long the session of this transaction has been open.
+    pub fn session_age(&self) -> Duration {
+        self.session.age()
+    }
+
+    /// How long until the session of this transaction passes the pool
+    /// `session_ttl`. Before a long batch, a transaction whose session is
+    /// about to expire can be rolled back and replaced by a fresher one.
+    pub fn session_remaining_ttl(&self) -> Duration {
+        self.session.remaining_ttl()
+    }
+
     /// Sends a query to QLDB. It will return an Array of IonValues
     /// already decoded. Parameters need to be provided using IonValue.
     pub fn query(&self, statement: &str) -> QueryBuilder {
         QueryBuilder::new(self.client.clone(), self.clone(), statement, self.auto_rollback)
     }
 
+    /// Executes the statements one after the other in this transaction,
+    /// with their params, and returns the documents returned by each one.
+    /// It stops at the first statement that fails. QLDB doesn't allow to
+    /// send several statements at once, but they are commited together.
+    ///
+    /// ```rust,no_run
+    /// # use qldb::{ion::IonValue, QldbResult, Transaction};
+    /// # async fn test(transaction: Transaction, parent: IonValue, child: IonValue) -> QldbResult<()> {
+    /// transaction
+    ///     .execute_batch(&[
+    ///         ("INSERT INTO Parents VALUE ?", vec![parent]),
+    ///         ("INSERT INTO Children VALUE ?", vec![child]),
+    ///     ])
+    ///     .await?;
+    ///
+    /// transaction.commit().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn execute_batch(&self, statements: &[(&str, Vec<IonValue>)]) -> QldbResult<Vec<DocumentCollection>> {
+        let mut results = Vec::with_capacity(statements.len());
+
+        for (statement, params) in statements {
+            // Executing the query would rollback auto rollback transactions
+            let result = self.query(statement).params(params).get_cursor()?.load_all().await?;
+
+            results.push(result);
+        }
+
+        if self.auto_rollback {
+            self.rollback().await?;
+        }
+
+        Ok(results)
+    }
+
     pub async fn commit(&self) -> QldbResult<()> {
         use TransactionStatus::*;
 
         let is_completed = self.completed.lock().await;
 
-        match *is_completed {
+        let commit_digest = match *is_completed {
             Commit => return Ok(()),
             Rollback => return Err(QldbError::TransactionAlreadyRollback),
-            Open => {
-                let commit_digest = self.hasher.lock().await.get().to_owned();
-
-                self.client
-                    .send_command(create_commit_command(
-                        self.session.get_session_id(),
-                        &self.transaction_id,
-                        &commit_digest,
-                    ))
-                    .await?;
-            }
-        }
+            Open => self.hasher.lock().await.get().to_owned(),
+        };
 
-        self.complete(is_completed, Commit);
+        let committing = self
+            .client
+            .send_command(create_commit_command(
+                self.session.get_session_id(),
+                &self.transaction_id,
+                &commit_digest,
+            ))
+            .compat();
+
+        let response = traced_command(
+            "commit_transaction",
+            Some(self.session.get_session_id()),
+            self.attempt,
+            self.session.observer(),
+            committing,
+        )
+        .await?;
 
-        // TODO: Check the returned CommitDigest with the
-        // current hash and fail if they are not equal.
+        self.complete(is_completed, Commit);
 
-        Ok(())
+        // QLDB calculates the digest on its side too, so a different one
+        // means that the statements or the results were altered on the way.
+        match response.commit_transaction.and_then(|result| result.commit_digest) {
+            Some(returned_digest) if returned_digest[..] != commit_digest[..] => Err(QldbError::CommitDigestMismatch),
+            _ => Ok(()),
+        }
     }
 
     pub(crate) async fn silent_commit(&self) -> QldbResult<()> {
@@ -113,9 +239,19 @@ impl Transaction {
             Rollback => return Ok(()),
             Commit => return Err(QldbError::TransactionAlreadyCommitted),
             Open => {
-                self.client
+                let aborting = self
+                    .client
                     .send_command(create_rollback_command(self.session.get_session_id()))
-                    .await?;
+                    .compat();
+
+                traced_command(
+                    "abort_transaction",
+                    Some(self.session.get_session_id()),
+                    self.attempt,
+                    self.session.observer(),
+                    aborting,
+                )
+                .await?;
             }
         }
 
@@ -151,6 +287,19 @@ impl Transaction {
         }
     }
 
+    /// Leaves the transaction as rolled back without telling QLDB. As we
+    /// don't know the state of the session (e.g. a statement may be still
+    /// running) it is closed instead of being given back to the pool, which
+    /// makes QLDB discard the transaction too.
+    pub(crate) async fn abandon(&self) {
+        let mut is_completed = self.completed.lock().await;
+
+        if let TransactionStatus::Open = *is_completed {
+            *is_completed = TransactionStatus::Rollback;
+            self.session_pool.give_back(self.session.invalidated());
+        }
+    }
+
     fn complete(&self, mut is_completed: MutexGuard<'_, TransactionStatus>, status: TransactionStatus) {
         *is_completed = status;
         self.session_pool.give_back((*self.session).clone());
@@ -169,8 +318,20 @@ impl Transaction {
         self.hasher.lock().await.dot(hasher);
     }
 
-    async fn get_transaction_id(client: &Arc<QldbSessionClient>, session: &str) -> QldbResult<String> {
-        let response = client.send_command(create_start_transaction_command(session)).await?;
+    async fn get_transaction_id(client: &Arc<DynQldbSession>, session: &Session, attempt: u32) -> QldbResult<String> {
+        let session_id = session.get_session_id();
+        let starting = client
+            .send_command(create_start_transaction_command(session_id))
+            .compat();
+
+        let response = traced_command(
+            "start_transaction",
+            Some(session_id),
+            attempt,
+            session.observer(),
+            starting,
+        )
+        .await?;
 
         let token = match response.start_transaction {
             Some(session) => match session.transaction_id {
@@ -1,7 +1,9 @@
+use crate::session_pool::SessionPoolError;
 use eyre::Report;
 use ion_binary_rs::IonParserError;
 use rusoto_core::{request::TlsError, RusotoError};
 use rusoto_qldb_session::SendCommandError;
+use std::fmt;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -18,6 +20,8 @@ pub enum QldbError {
     TlsError(#[from] TlsError),
     #[error("Transaction has been already commit or rollback")]
     TransactionCompleted,
+    #[error("The commit digest returned by QLDB doesn't match the one calculated for the transaction.")]
+    CommitDigestMismatch,
     #[error("We weren't able to send the result value to ourselves. This is a bug.")]
     InternalChannelSendError,
     #[error(
@@ -28,16 +32,134 @@ pub enum QldbError {
     TransactionAlreadyCommitted,
     #[error("The transaction is already rollback, it cannot be committed")]
     TransactionAlreadyRollback,
+    #[error("The statement didn't finish in time. The transaction has been cancelled.")]
+    StatementTimeout,
     #[error("The query was already executed. Trying to get a Cursor or executing it again will fail.")]
     QueryAlreadyExecuted,
+    #[error("The statement has {expected} parameters but {provided} were provided.")]
+    WrongParameterCount { expected: usize, provided: usize },
     #[error("Error extranting the QLDB returned Ion values to the requested type.")]
     QldbExtractError(#[from] QldbExtractError),
     #[error("Cannot get session from session pool. This means that the session pool was closed by calling the `.close()` method.")]
     SessionPoolClosed(Report),
+    #[error("The session pool stopped unexpectedly. Its background tasks are not running anymore, most likely because of a panic.")]
+    SessionPoolPanicked(Report),
+    #[error("The session pool couldn't close all the sessions.")]
+    SessionPoolCloseError(Report),
+    #[error("Timed out waiting for a session from the session pool.")]
+    SessionPoolTimeout,
+    #[error("QLDB didn't answer the health check in time.")]
+    HealthCheckTimeout,
+    #[error("The ledger name \"{0}\" is not valid. It needs 1 to 32 letters, digits or hyphens.")]
+    InvalidLedgerName(String),
+    #[error("The session pool configuration is not valid: {0}")]
+    InvalidPoolConfiguration(String),
+    #[error("The proxy URL \"{0}\" is not valid. It needs to be like http://host:port.")]
+    InvalidProxy(String),
+}
+
+impl QldbError {
+    /// Classifies the errors returned by the session pool, so closing the
+    /// pool on purpose can be told apart from the pool dying.
+    pub(crate) fn from_session_pool(report: Report) -> QldbError {
+        match report.downcast_ref::<SessionPoolError>() {
+            Some(SessionPoolError::Panicked) => QldbError::SessionPoolPanicked(report),
+            _ => QldbError::SessionPoolClosed(report),
+        }
+    }
+
+    /// QLDB rejected the transaction because some of the data it read was
+    /// changed by another transaction. Running the transaction again, e.g.
+    /// with `QldbClient::transact`, usually works.
+    pub fn is_occ_conflict(&self) -> bool {
+        matches!(self.send_command_error(), Some(SendCommandError::OccConflict(_)))
+    }
+
+    /// The session expired or QLDB doesn't know about it anymore.
+    pub fn is_invalid_session(&self) -> bool {
+        matches!(self.send_command_error(), Some(SendCommandError::InvalidSession(_)))
+    }
+
+    /// The requests are being sent faster than allowed, or faster than the
+    /// ledger can process them. It is worth retrying after a while.
+    pub fn is_rate_exceeded(&self) -> bool {
+        matches!(
+            self.send_command_error(),
+            Some(SendCommandError::RateExceeded(_)) | Some(SendCommandError::CapacityExceeded(_))
+        )
+    }
+
+    /// A QLDB limit, like the number of active sessions, was reached.
+    pub fn is_limit_exceeded(&self) -> bool {
+        matches!(self.send_command_error(), Some(SendCommandError::LimitExceeded(_)))
+    }
+
+    fn send_command_error(&self) -> Option<&SendCommandError> {
+        match self {
+            QldbError::SendCommandError(RusotoError::Service(err)) => Some(err),
+            _ => None,
+        }
+    }
 }
 
 pub type QldbResult<T> = Result<T, QldbError>;
 
+/// Header where AWS puts the id of the request
+const AWS_REQUEST_ID_HEADER: &str = "x-amzn-requestid";
+
+/// What AWS said about a failed request to create or end a session. It is
+/// the context of those errors, so it ends up in the logs and in
+/// `PoolEvent::CreationFailed`, e.g. to give the request id to AWS support.
+///
+/// Rusoto only keeps the response of the errors it doesn't recognize, so
+/// the request id is missing for the QLDB errors, like `RateExceeded`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct AwsErrorMetadata {
+    pub code: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl AwsErrorMetadata {
+    /// None when the error didn't come from AWS, e.g. a network error.
+    pub(crate) fn from_error(err: &RusotoError<SendCommandError>) -> Option<AwsErrorMetadata> {
+        match err {
+            RusotoError::Service(err) => {
+                let code = match err {
+                    SendCommandError::BadRequest(_) => "BadRequestException",
+                    SendCommandError::CapacityExceeded(_) => "CapacityExceededException",
+                    SendCommandError::InvalidSession(_) => "InvalidSessionException",
+                    SendCommandError::LimitExceeded(_) => "LimitExceededException",
+                    SendCommandError::OccConflict(_) => "OccConflictException",
+                    SendCommandError::RateExceeded(_) => "RateExceededException",
+                };
+
+                Some(AwsErrorMetadata {
+                    code: Some(code.to_string()),
+                    request_id: None,
+                })
+            }
+            RusotoError::Unknown(response) => Some(AwsErrorMetadata {
+                // e.g. "com.amazonaws.qldbsession#ThrottlingException"
+                code: rusoto_core::proto::json::Error::parse(response)
+                    .map(|error| error.typ.rsplit('#').next().unwrap_or_default().to_string()),
+                request_id: response.headers.get(AWS_REQUEST_ID_HEADER).cloned(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AwsErrorMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "AWS error code: {}, request id: {}",
+            self.code.as_deref().unwrap_or("unknown"),
+            self.request_id.as_deref().unwrap_or("unknown")
+        )
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum QldbExtractError {
     #[error("Cannot convert the IonValue to the requested type.")]
@@ -46,6 +168,11 @@ pub enum QldbExtractError {
     MissingProperty(String),
     #[error("Not a document. QLDB Documents must be an Ion::Struct, this is a: {0:?}")]
     NotADocument(ion_binary_rs::IonValue),
+    #[error("Cannot convert the document at index {index} to the requested type.")]
+    BadDocument {
+        index: usize,
+        source: Box<dyn std::error::Error + Send + Sync + 'static>,
+    },
 }
 
 pub type QldbExtractResult<T> = Result<T, QldbExtractError>;
@@ -0,0 +1,47 @@
+#![cfg(feature = "async-std")]
+
+use eyre::Result;
+use qldb::QldbClient;
+use rusoto_core::RusotoError;
+use rusoto_qldb_session::{
+    QldbSession, SendCommandError, SendCommandRequest, SendCommandResult, StartSessionResult, StartTransactionResult,
+};
+
+#[derive(Clone)]
+struct FakeSession;
+
+#[async_trait::async_trait]
+impl QldbSession for FakeSession {
+    async fn send_command(
+        &self,
+        _input: SendCommandRequest,
+    ) -> Result<SendCommandResult, RusotoError<SendCommandError>> {
+        Ok(SendCommandResult {
+            start_session: Some(StartSessionResult {
+                session_token: Some("session".to_string()),
+                ..Default::default()
+            }),
+            start_transaction: Some(StartTransactionResult {
+                transaction_id: Some("transaction".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+}
+
+#[async_std::test]
+async fn pool_runs_on_the_async_std_runtime() -> Result<()> {
+    let mut client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession)
+        .build_on_async_std()
+        .await?;
+
+    client.transaction().await?.rollback().await?;
+
+    assert_eq!(client.pool_stats().await?.idle, 1);
+
+    client.close().await?;
+
+    Ok(())
+}
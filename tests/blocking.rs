@@ -0,0 +1,87 @@
+#![cfg(feature = "blocking")]
+// The closures return QldbResult, whose error is big, see lib.rs
+#![allow(clippy::result_large_err)]
+
+mod local_qldb;
+
+use eyre::Result;
+use futures::executor::block_on;
+use local_qldb::LocalQldb;
+use qldb::ion::IonValue;
+use qldb::{blocking, QldbClient, QldbError};
+use rusoto_core::RusotoError;
+use rusoto_qldb_session::{
+    QldbSession, SendCommandError, SendCommandRequest, SendCommandResult, StartSessionResult, StartTransactionResult,
+};
+
+#[derive(Clone)]
+struct FakeSession;
+
+#[async_trait::async_trait]
+impl QldbSession for FakeSession {
+    async fn send_command(
+        &self,
+        _input: SendCommandRequest,
+    ) -> Result<SendCommandResult, RusotoError<SendCommandError>> {
+        Ok(SendCommandResult {
+            start_session: Some(StartSessionResult {
+                session_token: Some("session".to_string()),
+                ..Default::default()
+            }),
+            start_transaction: Some(StartTransactionResult {
+                transaction_id: Some("transaction".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+}
+
+#[test]
+fn failed_transactions_give_the_session_back() -> Result<()> {
+    let client = block_on(
+        QldbClient::builder("fake-ledger", 1)
+            .session_client(FakeSession)
+            .build(),
+    )?;
+    let mut client = blocking::QldbClient::from_async(client);
+
+    let result = client.transaction_within(|transaction| {
+        assert_eq!(transaction.ledger_name(), "fake-ledger");
+
+        Err::<(), _>(QldbError::TransactionCompleted)
+    });
+
+    assert!(matches!(result, Err(QldbError::TransactionCompleted)));
+    assert_eq!(client.pool_stats()?.idle, 1);
+
+    client.close()?;
+
+    Ok(())
+}
+
+#[test]
+fn transactions_work_over_a_real_connection_without_a_runtime() -> Result<()> {
+    use rusoto_core::credential::StaticProvider;
+
+    let local_qldb = LocalQldb::start()?;
+
+    let client = block_on(
+        QldbClient::builder("fake-ledger", 1)
+            .endpoint(&local_qldb.endpoint)
+            .credentials(StaticProvider::new_minimal("key".to_string(), "secret".to_string()))
+            .build(),
+    )?;
+    let mut client = blocking::QldbClient::from_async(client);
+
+    client.transaction_within(|transaction| {
+        transaction.execute("INSERT INTO TestTable VALUE ?", &[IonValue::String("test".to_string())])?;
+        Ok(())
+    })?;
+
+    assert!(client.read_query("SELECT * FROM TestTable", &[])?.is_empty());
+
+    client.close()?;
+
+    Ok(())
+}
@@ -1,6 +1,10 @@
-use ion_binary_rs::IonValue;
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, FixedOffset, Utc};
+use ion_binary_rs::{IonValue, NullIonValue};
 use qldb::{Document, DocumentCollection, QldbExtractError};
+use serde::Deserialize;
 use std::convert::{TryFrom, TryInto};
+use std::str::FromStr;
 
 fn get_qldb_struct() -> IonValue {
     IonValue::Struct(hashmap!(
@@ -34,7 +38,7 @@ fn check_document() {
     assert_eq!(new_value, "Sedan");
 
     let is_none = document.get_optional_value::<String>("Tipe").unwrap().is_none();
-    assert_eq!(is_none, true);
+    assert!(is_none);
 }
 
 #[test]
@@ -58,12 +62,110 @@ fn check_document_collection() {
         .collect::<Result<Vec<u64>, _>>()
         .unwrap()
         .into_iter()
-        .fold(0, |acc, val| acc + val);
+        .sum::<u64>();
 
     assert_eq!(documents, doc_collection);
     assert_eq!(value, 2019 * 3);
 }
 
+#[derive(Debug)]
+struct Car {
+    model: String,
+    year: u64,
+}
+
+impl TryFrom<Document> for Car {
+    type Error = QldbExtractError;
+
+    fn try_from(document: Document) -> Result<Self, Self::Error> {
+        Ok(Car {
+            model: document.get_value("Model")?,
+            year: document.get_value("Year")?,
+        })
+    }
+}
+
+#[test]
+fn check_document_collection_extract() {
+    let documents: DocumentCollection = vec![get_qldb_struct(), get_qldb_struct()].try_into().unwrap();
+
+    let cars = documents.extract::<Car>().unwrap();
+
+    assert_eq!(cars.len(), 2);
+    assert_eq!(cars[1].model, "CLK 350");
+    assert_eq!(cars[1].year, 2019);
+
+    let bad_struct = IonValue::Struct(hashmap!(
+        "Model".to_string() => IonValue::String("CLK 350".to_string())
+    ));
+
+    let documents: DocumentCollection = vec![get_qldb_struct(), bad_struct].try_into().unwrap();
+
+    match documents.extract::<Car>().unwrap_err() {
+        QldbExtractError::BadDocument { index, .. } => assert_eq!(index, 1),
+        _ => panic!(),
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+struct Sale {
+    model: String,
+    year: u16,
+    price: BigDecimal,
+    discount: f64,
+    sold_on: DateTime<FixedOffset>,
+    delivered_on: DateTime<Utc>,
+    extras: Vec<String>,
+    notes: Option<String>,
+}
+
+fn get_sale_struct() -> IonValue {
+    IonValue::Struct(hashmap!(
+        "Model".to_string() => IonValue::String("CLK 350".to_string()),
+        "Year".to_string() => IonValue::Integer(2019),
+        "Price".to_string() => IonValue::Decimal(BigDecimal::from_str("45999.99").unwrap()),
+        "Discount".to_string() => IonValue::Decimal(BigDecimal::from_str("0.15").unwrap()),
+        "SoldOn".to_string() => IonValue::DateTime(DateTime::parse_from_rfc3339("2021-03-04T10:20:30.123+02:00").unwrap()),
+        "DeliveredOn".to_string() => IonValue::DateTime(DateTime::parse_from_rfc3339("2021-03-05T08:00:00Z").unwrap()),
+        "Extras".to_string() => IonValue::List(vec![IonValue::Symbol("sunroof".to_string())]),
+        "Notes".to_string() => IonValue::Null(NullIonValue::String)
+    ))
+}
+
+#[test]
+fn check_document_collection_rows() {
+    let documents: DocumentCollection = vec![get_sale_struct()].try_into().unwrap();
+
+    let sales = documents.rows::<Sale>().unwrap();
+
+    assert_eq!(
+        sales,
+        vec![Sale {
+            model: "CLK 350".to_string(),
+            year: 2019,
+            price: BigDecimal::from_str("45999.99").unwrap(),
+            discount: 0.15,
+            sold_on: DateTime::parse_from_rfc3339("2021-03-04T10:20:30.123+02:00").unwrap(),
+            delivered_on: DateTime::parse_from_rfc3339("2021-03-05T08:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            extras: vec!["sunroof".to_string()],
+            notes: None,
+        }]
+    );
+
+    // The offset of the timestamp is kept
+    assert_eq!(sales[0].sold_on.offset().local_minus_utc(), 2 * 3600);
+
+    let documents: DocumentCollection = vec![get_sale_struct(), get_qldb_struct()].try_into().unwrap();
+
+    match documents.rows::<Sale>().unwrap_err() {
+        QldbExtractError::BadDocument { index, .. } => assert_eq!(index, 1),
+        _ => panic!(),
+    }
+}
+
 #[macro_export]
 macro_rules! hashmap(
     { $($key:expr => $value:expr),+ } => {
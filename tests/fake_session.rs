@@ -0,0 +1,1348 @@
+use eyre::Result;
+use futures::StreamExt;
+use qldb::{
+    Backoff, Clock, CommandMetrics, ManualClock, PolynomialBackoff, PoolEvent, Priority, QldbClient, QldbError,
+    RetryClassifier, Session, SessionObserver, SessionValidator, ThreadedSessionPool,
+};
+use rusoto_core::credential::CredentialsError;
+use rusoto_core::request::HttpDispatchError;
+use rusoto_core::{Region, RusotoError};
+use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher};
+use rusoto_qldb_session::{
+    QldbSession, QldbSessionClient, SendCommandError, SendCommandRequest, SendCommandResult, StartSessionResult,
+    StartTransactionResult,
+};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Answers every command successfully, except starting a session, which
+/// fails with the given errors before it starts to succeed.
+#[derive(Clone, Default)]
+struct FakeSession {
+    start_session_errors: Arc<Mutex<VecDeque<RusotoError<SendCommandError>>>>,
+    start_session_calls: Arc<AtomicU32>,
+    end_session_calls: Arc<AtomicU32>,
+    // Ending sessions never finishes, as with QLDB not answering
+    end_session_hangs: Arc<AtomicBool>,
+    end_session_fails: Arc<AtomicBool>,
+    // Starting a session waits until as many are being started
+    start_session_barrier: Option<Arc<async_lock::Barrier>>,
+    // Keep alives meet the test at the barrier, wait for it a second time
+    // and then fail as QLDB already expired the session
+    keep_alive_barrier: Option<Arc<async_lock::Barrier>>,
+}
+
+impl FakeSession {
+    fn failing_with(errors: Vec<RusotoError<SendCommandError>>) -> FakeSession {
+        FakeSession {
+            start_session_errors: Arc::new(Mutex::new(errors.into())),
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl QldbSession for FakeSession {
+    async fn send_command(
+        &self,
+        input: SendCommandRequest,
+    ) -> Result<SendCommandResult, RusotoError<SendCommandError>> {
+        if input.start_session.is_some() {
+            let calls = self.start_session_calls.fetch_add(1, Relaxed) + 1;
+
+            if let Some(barrier) = &self.start_session_barrier {
+                barrier.wait().await;
+            }
+
+            if let Some(err) = self.start_session_errors.lock().unwrap().pop_front() {
+                return Err(err);
+            }
+
+            return Ok(SendCommandResult {
+                start_session: Some(StartSessionResult {
+                    session_token: Some(format!("session-{}", calls)),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+        }
+
+        if input.end_session.is_some() {
+            self.end_session_calls.fetch_add(1, Relaxed);
+
+            if self.end_session_hangs.load(Relaxed) {
+                futures::future::pending::<()>().await;
+            }
+
+            if self.end_session_fails.load(Relaxed) {
+                return Err(network_error());
+            }
+        }
+
+        if let (true, Some(barrier)) = (is_keep_alive(&input), &self.keep_alive_barrier) {
+            barrier.wait().await;
+            barrier.wait().await;
+
+            return Err(RusotoError::Service(SendCommandError::InvalidSession(
+                "expired".to_string(),
+            )));
+        }
+
+        if input.start_transaction.is_some() {
+            return Ok(SendCommandResult {
+                start_transaction: Some(StartTransactionResult {
+                    transaction_id: Some("transaction".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+        }
+
+        Ok(SendCommandResult::default())
+    }
+}
+
+/// Commands with only the session token, which just refresh the session.
+fn is_keep_alive(input: &SendCommandRequest) -> bool {
+    input.session_token.is_some()
+        && input.start_session.is_none()
+        && input.end_session.is_none()
+        && input.start_transaction.is_none()
+        && input.execute_statement.is_none()
+        && input.fetch_page.is_none()
+        && input.commit_transaction.is_none()
+        && input.abort_transaction.is_none()
+}
+
+#[derive(Debug)]
+struct NoBackoff;
+
+impl Backoff for NoBackoff {
+    fn next_delay(&self, _attempt: u32) -> Duration {
+        Duration::ZERO
+    }
+}
+
+fn network_error() -> RusotoError<SendCommandError> {
+    RusotoError::HttpDispatch(HttpDispatchError::new("connection reset".to_string()))
+}
+
+fn credentials_error() -> RusotoError<SendCommandError> {
+    RusotoError::Credentials(CredentialsError::new("token expired"))
+}
+
+#[async_std::test]
+async fn recoverable_errors_are_retried() -> Result<()> {
+    let session = FakeSession::failing_with(vec![network_error(), network_error(), network_error()]);
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .max_session_retries(3)
+        .backoff(Arc::new(NoBackoff))
+        .build()
+        .await?;
+
+    client.transaction_with_timeout(Duration::from_secs(1)).await?;
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 4);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn creation_gives_up_after_the_max_retries() -> Result<()> {
+    let session = FakeSession::failing_with(vec![network_error(), network_error(), network_error()]);
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .max_session_retries(1)
+        .backoff(Arc::new(NoBackoff))
+        .build()
+        .await?;
+
+    // The pool waits a bit before trying again after giving up
+    assert!(client
+        .transaction_with_timeout(Duration::from_millis(20))
+        .await
+        .is_err());
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn credential_errors_are_not_retried_by_default() -> Result<()> {
+    let session = FakeSession::failing_with(vec![credentials_error()]);
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .backoff(Arc::new(NoBackoff))
+        .build()
+        .await?;
+
+    assert!(client
+        .transaction_with_timeout(Duration::from_millis(20))
+        .await
+        .is_err());
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn credential_errors_can_be_retried() -> Result<()> {
+    let session = FakeSession::failing_with(vec![credentials_error()]);
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .retry_credential_errors(true)
+        .backoff(Arc::new(NoBackoff))
+        .build()
+        .await?;
+
+    client.transaction_with_timeout(Duration::from_millis(20)).await?;
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn expired_sessions_are_closed_when_given_back() -> Result<()> {
+    let session = FakeSession::default();
+    let clock = Arc::new(ManualClock::new());
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .session_ttl(Duration::from_secs(60))
+        .clock(clock.clone())
+        .build()
+        .await?;
+
+    client.transaction().await?.rollback().await?;
+
+    assert_eq!(client.pool_stats().await?.idle, 1);
+
+    let transaction = client.transaction().await?;
+
+    clock.advance(Duration::from_secs(61));
+
+    transaction.rollback().await?;
+
+    let stats = client.pool_stats().await?;
+
+    assert_eq!(stats.idle, 0);
+    assert_eq!(session.start_session_calls.load(Relaxed), 1);
+
+    // Closing the session happens in the background
+    for _ in 0..100 {
+        if session.end_session_calls.load(Relaxed) == 1 {
+            return Ok(());
+        }
+
+        async_std::task::sleep(Duration::from_millis(1)).await;
+    }
+
+    panic!("The expired session wasn't closed");
+}
+
+#[async_std::test]
+async fn retries_wait_on_the_clock() -> Result<()> {
+    let session = FakeSession::failing_with(vec![network_error(), network_error()]);
+    let clock = Arc::new(ManualClock::new());
+    let started_on = clock.now();
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .clock(clock.clone())
+        .build()
+        .await?;
+
+    // The default backoff waits 75ms and 300ms, which the clock skips
+    client.transaction_with_timeout(Duration::from_millis(100)).await?;
+
+    assert_eq!(clock.now() - started_on, Duration::from_millis(375));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn closed_completes_when_another_clone_closes_the_pool() -> Result<()> {
+    let mut client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .build()
+        .await?;
+
+    let watching = client.clone();
+    let watcher = async_std::task::spawn(async move { watching.closed().await });
+
+    client.close().await?;
+
+    async_std::future::timeout(Duration::from_secs(1), watcher).await?;
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn closing_twice_returns_the_same_result() -> Result<()> {
+    let mut client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .build()
+        .await?;
+    let mut other_client = client.clone();
+
+    let (first, second) = futures::join!(client.close(), other_client.close());
+
+    assert!(first.is_ok());
+    assert!(second.is_ok());
+    assert!(client.close().await.is_ok());
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn transactions_report_the_age_of_their_session() -> Result<()> {
+    let clock = Arc::new(ManualClock::new());
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .session_ttl(Duration::from_secs(60))
+        .clock(clock.clone())
+        .build()
+        .await?;
+
+    let transaction = client.transaction().await?;
+
+    clock.advance(Duration::from_secs(45));
+
+    assert_eq!(transaction.session_age(), Duration::from_secs(45));
+    assert_eq!(transaction.session_remaining_ttl(), Duration::from_secs(15));
+
+    clock.advance(Duration::from_secs(30));
+
+    assert_eq!(transaction.session_remaining_ttl(), Duration::ZERO);
+
+    transaction.rollback().await?;
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn sessions_are_closed_after_the_max_uses() -> Result<()> {
+    let session = FakeSession::default();
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .max_session_uses(2)
+        .build()
+        .await?;
+
+    client.transaction().await?.rollback().await?;
+
+    assert_eq!(client.pool_stats().await?.idle, 1);
+
+    client.transaction().await?.rollback().await?;
+
+    assert_eq!(client.pool_stats().await?.idle, 0);
+
+    client.transaction().await?.rollback().await?;
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn expired_sessions_are_replaced_without_waiting_for_their_close() -> Result<()> {
+    let session = FakeSession::default();
+    let clock = Arc::new(ManualClock::new());
+
+    session.end_session_hangs.store(true, Relaxed);
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .session_ttl(Duration::from_secs(60))
+        .clock(clock.clone())
+        .build()
+        .await?;
+
+    client.transaction().await?.rollback().await?;
+
+    assert_eq!(client.pool_stats().await?.idle, 1);
+
+    clock.advance(Duration::from_secs(61));
+
+    // The only slot is taken by the expired session until QLDB ends it
+    client.transaction_with_timeout(Duration::from_secs(1)).await?;
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 2);
+    assert_eq!(session.end_session_calls.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct NetworkErrorsAreFinal;
+
+impl RetryClassifier for NetworkErrorsAreFinal {
+    fn is_recoverable(&self, err: &RusotoError<SendCommandError>) -> bool {
+        !matches!(err, RusotoError::HttpDispatch(_))
+    }
+}
+
+#[async_std::test]
+async fn errors_classified_as_unrecoverable_are_not_retried() -> Result<()> {
+    let session = FakeSession::failing_with(vec![network_error()]);
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .retry_classifier(Arc::new(NetworkErrorsAreFinal))
+        .backoff(Arc::new(NoBackoff))
+        .build()
+        .await?;
+
+    assert!(client
+        .transaction_with_timeout(Duration::from_millis(20))
+        .await
+        .is_err());
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn dropped_requests_stop_waiting_for_a_session() -> Result<()> {
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .build()
+        .await?;
+
+    let transaction = client.transaction().await?;
+
+    // The only session is in use, so the request waits until it is dropped
+    let waiting = async_std::future::timeout(Duration::from_millis(50), client.transaction()).await;
+
+    assert!(waiting.is_err());
+    assert_eq!(client.pending_requests().await?, 0);
+
+    transaction.rollback().await?;
+
+    // The session is given back without waiting for the pool to store it
+    for _ in 0..100 {
+        if client.pool_stats().await?.idle == 1 {
+            return Ok(());
+        }
+
+        async_std::task::sleep(Duration::from_millis(1)).await;
+    }
+
+    panic!("The session never went back to the pool");
+}
+
+#[derive(Debug, Default)]
+struct RecordingObserver {
+    commands: Mutex<Vec<CommandMetrics>>,
+}
+
+impl SessionObserver for RecordingObserver {
+    fn on_command(&self, metrics: &CommandMetrics) {
+        self.commands.lock().unwrap().push(*metrics);
+    }
+}
+
+#[async_std::test]
+async fn every_command_is_reported_to_the_observer() -> Result<()> {
+    let observer = Arc::new(RecordingObserver::default());
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::failing_with(vec![network_error()]))
+        .session_observer(observer.clone())
+        .backoff(Arc::new(NoBackoff))
+        .build()
+        .await?;
+
+    client.transaction().await?.rollback().await?;
+
+    let commands = observer.commands.lock().unwrap().clone();
+    let commands: Vec<_> = commands
+        .iter()
+        .map(|metrics| (metrics.command, metrics.attempt, metrics.succeeded))
+        .collect();
+
+    assert_eq!(
+        commands,
+        vec![
+            ("start_session", 1, false),
+            ("start_session", 2, true),
+            ("start_transaction", 1, true),
+            ("abort_transaction", 1, true),
+        ]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn transact_reports_the_try_of_the_transaction_commands() -> Result<()> {
+    let observer = Arc::new(RecordingObserver::default());
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .session_observer(observer.clone())
+        .backoff(Arc::new(NoBackoff))
+        .build()
+        .await?;
+
+    let tries = AtomicU32::new(0);
+
+    client
+        .transact(|_| async {
+            if tries.fetch_add(1, Relaxed) == 0 {
+                Err(QldbError::SendCommandError(RusotoError::Service(
+                    SendCommandError::OccConflict("conflict".to_string()),
+                )))
+            } else {
+                Ok(())
+            }
+        })
+        .await?;
+
+    let commands = observer.commands.lock().unwrap().clone();
+    let commands: Vec<_> = commands
+        .iter()
+        .filter(|metrics| metrics.command != "start_session")
+        .map(|metrics| (metrics.command, metrics.attempt))
+        .collect();
+
+    assert_eq!(
+        commands,
+        vec![
+            ("start_transaction", 1),
+            ("abort_transaction", 1),
+            ("start_transaction", 2),
+            ("commit_transaction", 2),
+        ]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn pool_stops_growing_at_the_qldb_session_limit() -> Result<()> {
+    let session = FakeSession::default();
+
+    let client = QldbClient::builder("fake-ledger", 2)
+        .session_client(session.clone())
+        .backoff(Arc::new(NoBackoff))
+        .build()
+        .await?;
+
+    let transaction = client.transaction().await?;
+
+    session
+        .start_session_errors
+        .lock()
+        .unwrap()
+        .push_back(RusotoError::Service(SendCommandError::LimitExceeded(
+            "too many sessions".to_string(),
+        )));
+
+    // The second transaction waits for the first one instead of retrying
+    let (waiting, _) = futures::join!(client.transaction_with_timeout(Duration::from_secs(1)), async {
+        async_std::task::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(session.start_session_calls.load(Relaxed), 2);
+
+        transaction.rollback().await
+    });
+
+    waiting?;
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn drained_pools_open_new_sessions_when_needed() -> Result<()> {
+    let session = FakeSession::default();
+
+    let client = QldbClient::builder("fake-ledger", 2)
+        .session_client(session.clone())
+        .build()
+        .await?;
+
+    let first = client.transaction().await?;
+    let second = client.transaction().await?;
+    first.rollback().await?;
+    second.rollback().await?;
+
+    assert_eq!(client.pool_stats().await?.idle, 2);
+
+    assert_eq!(client.drain_idle().await?, 2);
+
+    async_std::task::sleep(Duration::from_millis(50)).await;
+
+    let stats = client.pool_stats().await?;
+    assert_eq!((stats.active, stats.idle), (0, 0));
+    assert_eq!(session.end_session_calls.load(Relaxed), 2);
+
+    client.transaction().await?.rollback().await?;
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 3);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn high_priority_requests_are_served_first() -> Result<()> {
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .build()
+        .await?;
+
+    let transaction = client.transaction().await?;
+
+    let served = Arc::new(Mutex::new(Vec::new()));
+    let mut waiters = Vec::new();
+
+    for (name, priority) in [
+        ("normal-1", Priority::Normal),
+        ("normal-2", Priority::Normal),
+        ("high", Priority::High),
+    ] {
+        let client = client.clone();
+        let served = served.clone();
+
+        waiters.push(async_std::task::spawn(async move {
+            let transaction = client.transaction_with_priority(priority).await?;
+            served.lock().unwrap().push(name);
+            transaction.rollback().await
+        }));
+
+        // So they are queued in order
+        async_std::task::sleep(Duration::from_millis(20)).await;
+    }
+
+    transaction.rollback().await?;
+
+    for waiter in waiters {
+        waiter.await?;
+    }
+
+    assert_eq!(*served.lock().unwrap(), vec!["high", "normal-1", "normal-2"]);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn subscribers_receive_the_pool_events_in_order() -> Result<()> {
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .build()
+        .await?;
+
+    let mut events = client.subscribe();
+
+    client.transaction().await?.rollback().await?;
+
+    let mut received = Vec::new();
+    for _ in 0..4 {
+        received.push(async_std::future::timeout(Duration::from_secs(1), events.next()).await?);
+    }
+
+    let session_id = "session-1".to_string();
+
+    assert_eq!(
+        received,
+        vec![
+            Some(PoolEvent::RequestQueued),
+            Some(PoolEvent::SessionCreated {
+                session_id: session_id.clone()
+            }),
+            Some(PoolEvent::SessionBorrowed {
+                session_id: session_id.clone()
+            }),
+            Some(PoolEvent::SessionReturned { session_id }),
+        ]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn creations_wait_the_retry_delay_after_giving_up() -> Result<()> {
+    let session = FakeSession::failing_with(vec![network_error(), network_error(), network_error()]);
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .max_session_retries(1)
+        .backoff(Arc::new(NoBackoff))
+        .creation_retry_delay(Duration::from_secs(10))
+        .build()
+        .await?;
+
+    // With the default delay it would have succeeded on the second round
+    assert!(client
+        .transaction_with_timeout(Duration::from_millis(300))
+        .await
+        .is_err());
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn pool_closes_when_the_shutdown_signal_fires() -> Result<()> {
+    let (shutdown, shutdown_receiver) = futures::channel::oneshot::channel::<()>();
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .shutdown_signal(async move {
+            let _ = shutdown_receiver.await;
+        })
+        .build()
+        .await?;
+
+    client.transaction().await?.rollback().await?;
+
+    let _ = shutdown.send(());
+
+    async_std::future::timeout(Duration::from_secs(1), client.closed()).await?;
+
+    assert!(client.transaction().await.is_err());
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn dropping_the_client_closes_its_idle_sessions() -> Result<()> {
+    let session = FakeSession::default();
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .build()
+        .await?;
+
+    client.transaction().await?.rollback().await?;
+
+    drop(client);
+
+    for _ in 0..100 {
+        if session.end_session_calls.load(Relaxed) == 1 {
+            return Ok(());
+        }
+
+        async_std::task::sleep(Duration::from_millis(10)).await;
+    }
+
+    panic!("The idle session wasn't closed");
+}
+
+#[async_std::test]
+async fn sessions_are_closed_up_to_the_close_concurrency_at_once() -> Result<()> {
+    let session = FakeSession::default();
+    session.end_session_hangs.store(true, Relaxed);
+
+    let client = QldbClient::builder("fake-ledger", 3)
+        .session_client(session.clone())
+        .close_concurrency(1)
+        .build()
+        .await?;
+
+    let transactions = vec![
+        client.transaction().await?,
+        client.transaction().await?,
+        client.transaction().await?,
+    ];
+
+    for transaction in transactions {
+        transaction.rollback().await?;
+    }
+
+    assert_eq!(client.drain_idle().await?, 3);
+
+    async_std::task::sleep(Duration::from_millis(50)).await;
+
+    // The first close never finishes, so the others wait for its permit
+    assert_eq!(session.end_session_calls.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn creation_failures_report_the_aws_request_id() -> Result<()> {
+    let dispatcher = MockRequestDispatcher::with_status(400)
+        .with_body(r#"{"__type":"com.amazonaws.qldbsession#ThrottlingException","message":"Slow down"}"#)
+        .with_header("x-amzn-RequestId", "request-1");
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(QldbSessionClient::new_with(
+            dispatcher,
+            MockCredentialsProvider,
+            Region::UsEast1,
+        ))
+        .max_session_retries(1)
+        .backoff(Arc::new(NoBackoff))
+        .build()
+        .await?;
+
+    let mut events = client.subscribe();
+
+    let _ = client.transaction_with_timeout(Duration::from_millis(20)).await;
+
+    let error = loop {
+        match async_std::future::timeout(Duration::from_secs(1), events.next()).await? {
+            Some(PoolEvent::CreationFailed { error }) => break error,
+            Some(_) => continue,
+            None => panic!("The pool stopped without reporting the failure"),
+        }
+    };
+
+    assert!(
+        error.contains("AWS error code: ThrottlingException, request id: request-1"),
+        "{}",
+        error
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn overflow_sessions_are_closed_once_given_back() -> Result<()> {
+    let session = FakeSession::default();
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .allow_overflow(1)
+        .build()
+        .await?;
+
+    let first = client.transaction().await?;
+    let second = client.transaction_with_timeout(Duration::from_secs(1)).await?;
+
+    // Only one over the max
+    assert!(client
+        .transaction_with_timeout(Duration::from_millis(20))
+        .await
+        .is_err());
+
+    let stats = client.pool_stats().await?;
+    assert_eq!(stats.active, 2);
+    assert_eq!(stats.overflow_sessions_created, 1);
+
+    second.rollback().await?;
+    first.rollback().await?;
+
+    for _ in 0..100 {
+        let stats = client.pool_stats().await?;
+
+        if session.end_session_calls.load(Relaxed) == 1 && stats.active == 1 {
+            assert_eq!(stats.idle, 1);
+            return Ok(());
+        }
+
+        async_std::task::sleep(Duration::from_millis(1)).await;
+    }
+
+    panic!("The overflow session wasn't closed");
+}
+
+#[async_std::test]
+async fn sessions_over_max_idle_are_closed_when_given_back() -> Result<()> {
+    let session = FakeSession::default();
+
+    let client = QldbClient::builder("fake-ledger", 3)
+        .session_client(session.clone())
+        .max_idle(1)
+        .build()
+        .await?;
+
+    let transactions = vec![
+        client.transaction().await?,
+        client.transaction().await?,
+        client.transaction().await?,
+    ];
+
+    for transaction in transactions {
+        transaction.rollback().await?;
+    }
+
+    for _ in 0..100 {
+        let stats = client.pool_stats().await?;
+
+        if session.end_session_calls.load(Relaxed) == 2 && stats.active == 1 {
+            assert_eq!(stats.idle, 1);
+            return Ok(());
+        }
+
+        async_std::task::sleep(Duration::from_millis(1)).await;
+    }
+
+    panic!("The sessions over max_idle weren't closed");
+}
+
+#[derive(Debug, Default)]
+struct RejectingValidator {
+    rejected_session: Mutex<Option<String>>,
+}
+
+impl SessionValidator for RejectingValidator {
+    fn is_valid(&self, session: &Session) -> bool {
+        self.rejected_session.lock().unwrap().as_deref() != Some(session.get_session_id())
+    }
+}
+
+#[async_std::test]
+async fn sessions_rejected_by_the_validator_are_replaced() -> Result<()> {
+    let session = FakeSession::default();
+    let validator = Arc::new(RejectingValidator::default());
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .session_validator(validator.clone())
+        .build()
+        .await?;
+
+    client.transaction().await?.rollback().await?;
+    client.transaction().await?.rollback().await?;
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 1);
+
+    *validator.rejected_session.lock().unwrap() = Some("session-1".to_string());
+
+    client.transaction().await?.rollback().await?;
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 2);
+    assert_eq!(client.idle_session_ids().await?, vec!["session-2".to_string()]);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn pools_with_all_the_sessions_in_use_are_saturated() -> Result<()> {
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .build()
+        .await?;
+
+    assert!(!client.is_saturated().await?);
+
+    let transaction = client.transaction().await?;
+
+    assert!(client.is_saturated().await?);
+
+    transaction.rollback().await?;
+
+    // The session is given back in the background
+    async_std::task::sleep(Duration::from_millis(10)).await;
+
+    assert!(!client.is_saturated().await?);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn bursts_of_requests_create_their_sessions_at_once() -> Result<()> {
+    // No session is created until the three are being created
+    let session = FakeSession {
+        start_session_barrier: Some(Arc::new(async_lock::Barrier::new(3))),
+        ..Default::default()
+    };
+
+    let client = QldbClient::builder("fake-ledger", 3)
+        .session_client(session.clone())
+        .creation_concurrency(3)
+        .build()
+        .await?;
+
+    let transactions =
+        futures::future::try_join_all((0..3).map(|_| client.transaction_with_timeout(Duration::from_secs(1)))).await?;
+
+    assert_eq!(session.start_session_calls.load(Relaxed), 3);
+
+    for transaction in transactions {
+        transaction.rollback().await?;
+    }
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn transactions_get_a_session_each() -> Result<()> {
+    let session = FakeSession::default();
+
+    let client = QldbClient::builder("fake-ledger", 3)
+        .session_client(session.clone())
+        .build()
+        .await?;
+
+    let transactions = client.transactions(3).await?;
+
+    assert_eq!(transactions.len(), 3);
+    assert_eq!(session.start_session_calls.load(Relaxed), 3);
+    assert!(client.is_saturated().await?);
+
+    for transaction in transactions {
+        transaction.rollback().await?;
+    }
+
+    async_std::task::sleep(Duration::from_millis(10)).await;
+
+    assert_eq!(client.pool_stats().await?.idle, 3);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn polynomial_backoff_waits_from_its_base_delay() -> Result<()> {
+    let session = FakeSession::failing_with(vec![network_error(), network_error(), network_error()]);
+    let clock = Arc::new(ManualClock::new());
+    let started_on = clock.now();
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .clock(clock.clone())
+        .backoff(Arc::new(PolynomialBackoff::new(Duration::from_millis(10), 2)))
+        .build()
+        .await?;
+
+    // 10ms, 40ms and 90ms, which the clock skips
+    client.transaction_with_timeout(Duration::from_millis(100)).await?;
+
+    assert_eq!(clock.now() - started_on, Duration::from_millis(140));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn the_last_creation_error_is_kept_until_a_session_is_created() -> Result<()> {
+    let session = FakeSession::failing_with(vec![credentials_error()]);
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .creation_retry_delay(Duration::from_millis(50))
+        .build()
+        .await?;
+
+    assert!(client.last_creation_error().is_none());
+
+    assert!(client
+        .transaction_with_timeout(Duration::from_millis(20))
+        .await
+        .is_err());
+
+    let error = client.last_creation_error().expect("The creation should have failed");
+    assert!(error.contains("token expired"), "{}", error);
+
+    client.transaction_with_timeout(Duration::from_secs(1)).await?;
+
+    assert!(client.last_creation_error().is_none());
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn warm_pools_have_the_target_sessions_ready() -> Result<()> {
+    let session = FakeSession::default();
+
+    let client = QldbClient::builder("fake-ledger", 3)
+        .session_client(session.clone())
+        .creation_concurrency(3)
+        .build()
+        .await?;
+
+    client.warm(2, Duration::from_secs(1)).await?;
+
+    let stats = client.pool_stats().await?;
+    assert_eq!((stats.active, stats.idle), (2, 2));
+    assert_eq!((stats.served_from_pool, stats.served_from_new), (0, 0));
+    assert_eq!(session.start_session_calls.load(Relaxed), 2);
+
+    assert!(matches!(
+        client.warm(4, Duration::from_secs(1)).await,
+        Err(QldbError::InvalidPoolConfiguration(_))
+    ));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn warm_pools_count_the_sessions_in_use() -> Result<()> {
+    let session = FakeSession::default();
+
+    let client = QldbClient::builder("fake-ledger", 2)
+        .session_client(session.clone())
+        .max_idle(1)
+        .build()
+        .await?;
+
+    let transaction = client.transaction().await?;
+
+    // Only one more is needed, the one in use cannot be checked out
+    client.warm(2, Duration::from_millis(100)).await?;
+
+    let stats = client.pool_stats().await?;
+    assert_eq!((stats.active, stats.idle), (2, 1));
+    assert_eq!(session.start_session_calls.load(Relaxed), 2);
+
+    // Already warm, nothing else is created
+    client.warm(1, Duration::from_millis(100)).await?;
+    assert_eq!(session.start_session_calls.load(Relaxed), 2);
+
+    transaction.rollback().await?;
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn pool_stats_count_the_sessions_reused() -> Result<()> {
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .build()
+        .await?;
+
+    assert_eq!(client.pool_stats().await?.reuse_ratio(), None);
+
+    for _ in 0..4 {
+        client.transaction().await?.rollback().await?;
+
+        // The session is given back in the background
+        async_std::task::sleep(Duration::from_millis(10)).await;
+    }
+
+    let stats = client.pool_stats().await?;
+    assert_eq!((stats.served_from_new, stats.served_from_pool), (1, 3));
+    assert_eq!(stats.reuse_ratio(), Some(0.75));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn closing_gives_up_after_the_max_close_retries() -> Result<()> {
+    let session = FakeSession::default();
+    session.end_session_fails.store(true, Relaxed);
+
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .max_close_retries(1)
+        .backoff(Arc::new(NoBackoff))
+        .build()
+        .await?;
+
+    client.transaction().await?.rollback().await?;
+
+    // The session is given back in the background
+    async_std::task::sleep(Duration::from_millis(10)).await;
+
+    assert_eq!(client.drain_idle().await?, 1);
+
+    async_std::task::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(session.end_session_calls.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn placeholders_in_literals_and_comments_are_not_parameters() -> Result<()> {
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .build()
+        .await?;
+
+    for statement in [
+        "SELECT * FROM t WHERE a = ?",
+        "SELECT * FROM t WHERE a = 'really?' AND b = ?",
+        "SELECT * FROM t WHERE a = 'it''s ?' AND b = ?",
+        "SELECT \"why?\" FROM t WHERE b = ?",
+        "SELECT \"say \"\"what?\"\"\" FROM t WHERE b = ?",
+        "SELECT * FROM t WHERE a = `{b: \"c?\"}` AND b = ?",
+        "SELECT * FROM t -- is it ?\nWHERE b = ?",
+        "SELECT * FROM t /* is it ? */ WHERE b = ?",
+        "SELECT * FROM t /* is it *? */ WHERE b = ?",
+        "SELECT * FROM t WHERE b = ? /* unterminated ?",
+    ] {
+        let transaction = client.transaction().await?;
+
+        transaction
+            .query(statement)
+            .param(1)
+            .execute()
+            .await
+            .map_err(|err| eyre::eyre!("{:?} was rejected: {}", statement, err))?;
+
+        transaction.rollback().await?;
+    }
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn statements_with_a_wrong_number_of_parameters_are_rejected() -> Result<()> {
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .build()
+        .await?;
+
+    let transaction = client.transaction().await?;
+
+    let result = transaction
+        .query("SELECT * FROM t WHERE a = ? AND b = ?")
+        .param(1)
+        .execute()
+        .await;
+
+    assert!(
+        matches!(
+            result,
+            Err(QldbError::WrongParameterCount {
+                expected: 2,
+                provided: 1
+            })
+        ),
+        "{:?}",
+        result
+    );
+
+    transaction.rollback().await?;
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn sessions_discarded_while_closing_are_closed() -> Result<()> {
+    let barrier = Arc::new(async_lock::Barrier::new(2));
+    let session = FakeSession {
+        keep_alive_barrier: Some(barrier.clone()),
+        ..Default::default()
+    };
+
+    let mut client = QldbClient::builder("fake-ledger", 1)
+        .session_client(session.clone())
+        .close_grace_period(Duration::from_secs(5))
+        .build()
+        .await?;
+
+    let checking_client = client.clone();
+    let checking = async_std::task::spawn(async move { checking_client.health_check(Duration::from_secs(5)).await });
+
+    // The health check has the session, which QLDB says it expired
+    // once the pool is closing
+    barrier.wait().await;
+
+    let started_on = Instant::now();
+
+    let (closed, _) = futures::join!(client.close(), async {
+        async_std::task::sleep(Duration::from_millis(20)).await;
+        barrier.wait().await;
+    });
+
+    assert!(closed.is_ok(), "{:?}", closed);
+    assert!(started_on.elapsed() < Duration::from_secs(5));
+    assert!(checking.await.unwrap_err().is_invalid_session());
+    assert_eq!(session.end_session_calls.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn the_close_grace_period_waits_on_the_clock() -> Result<()> {
+    let clock = Arc::new(ManualClock::new());
+    let started_on = clock.now();
+    let session = FakeSession::default();
+
+    let mut client = QldbClient::builder("fake-ledger", 2)
+        .session_client(session.clone())
+        .clock(clock.clone())
+        .close_grace_period(Duration::from_secs(60))
+        .build()
+        .await?;
+
+    // One session idle, which is closed, and one in use until the end
+    let (idle, _in_use) = futures::try_join!(client.transaction(), client.transaction())?;
+    idle.rollback().await?;
+
+    // The session is given back in the background
+    async_std::task::sleep(Duration::from_millis(10)).await;
+
+    let real_started_on = Instant::now();
+
+    assert!(matches!(client.close().await, Err(QldbError::SessionPoolCloseError(_))));
+
+    assert!(real_started_on.elapsed() < Duration::from_secs(5));
+    assert_eq!(clock.now() - started_on, Duration::from_secs(60));
+    assert_eq!(session.end_session_calls.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn requests_past_the_queue_capacity_take_the_place_of_the_ones_that_gave_up() -> Result<()> {
+    let client = QldbClient::builder("fake-ledger", 1)
+        .session_client(FakeSession::default())
+        .request_queue_capacity(1)
+        .build()
+        .await?;
+
+    let transaction = client.transaction().await?;
+
+    // The first request takes the only place in the queue, so the second one
+    // is left in the channel until the first one gives up
+    let giving_up_client = client.clone();
+    let giving_up = async_std::task::spawn(async move {
+        giving_up_client
+            .transaction_with_timeout(Duration::from_millis(50))
+            .await
+    });
+    async_std::task::sleep(Duration::from_millis(10)).await;
+
+    let waiting_client = client.clone();
+    let waiting = async_std::task::spawn(async move { waiting_client.transaction().await });
+
+    assert!(giving_up.await.is_err());
+
+    let queued = async {
+        while client.pool_stats().await?.waiters == 0 {
+            async_std::task::sleep(Duration::from_millis(1)).await;
+        }
+
+        Ok::<_, QldbError>(())
+    };
+    async_std::future::timeout(Duration::from_secs(5), queued).await??;
+
+    transaction.rollback().await?;
+
+    let transaction = async_std::future::timeout(Duration::from_secs(5), waiting).await??;
+    transaction.rollback().await?;
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn pools_created_on_their_own_are_validated() -> Result<()> {
+    let client = Arc::new(QldbSessionClient::new_with(
+        MockRequestDispatcher::default(),
+        MockCredentialsProvider,
+        Region::UsEast1,
+    ));
+
+    assert!(matches!(
+        ThreadedSessionPool::new(client.clone(), "fake-ledger", 0),
+        Err(QldbError::InvalidPoolConfiguration(_))
+    ));
+    assert!(matches!(
+        ThreadedSessionPool::new(client.clone(), "", 1),
+        Err(QldbError::InvalidLedgerName(_))
+    ));
+
+    ThreadedSessionPool::new(client, "fake-ledger", 1)?.close().await?;
+
+    Ok(())
+}
@@ -0,0 +1,85 @@
+//! A plain HTTP server answering like QLDB, so the tests can go through
+//! the real rusoto and hyper client without AWS.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
+use std::sync::Arc;
+
+pub struct LocalQldb {
+    pub endpoint: String,
+    #[allow(dead_code)]
+    pub start_session_calls: Arc<AtomicU32>,
+}
+
+impl LocalQldb {
+    pub fn start() -> io::Result<LocalQldb> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        let start_session_calls = Arc::new(AtomicU32::new(0));
+
+        let counting = start_session_calls.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let counting = counting.clone();
+                std::thread::spawn(move || serve(stream, &counting));
+            }
+        });
+
+        Ok(LocalQldb {
+            endpoint,
+            start_session_calls,
+        })
+    }
+}
+
+fn serve(stream: TcpStream, start_session_calls: &AtomicU32) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    // The connections are kept alive, so one request comes after the other
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        let mut content_length = 0;
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        let body = String::from_utf8_lossy(&body);
+
+        let answer = if body.contains("\"StartSession\"") {
+            start_session_calls.fetch_add(1, Relaxed);
+            r#"{"StartSession":{"SessionToken":"session"}}"#
+        } else if body.contains("\"StartTransaction\"") {
+            r#"{"StartTransaction":{"TransactionId":"transaction"}}"#
+        } else if body.contains("\"ExecuteStatement\"") {
+            r#"{"ExecuteStatement":{"FirstPage":{"Values":[]}}}"#
+        } else {
+            "{}"
+        };
+
+        write!(
+            writer,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-amz-json-1.0\r\nContent-Length: {}\r\n\r\n{}",
+            answer.len(),
+            answer
+        )?;
+    }
+}
@@ -142,7 +142,7 @@ async fn qldb_type_list() -> Result<()> {
     create_type_test(get_value_to_insert_list(), |values| {
         assert_eq!(
             values.get("1").unwrap(),
-            &IonValue::List(vec!["list", "of", "strings"].iter().map(|v| v.into()).collect())
+            &IonValue::List(["list", "of", "strings"].iter().map(|v| v.into()).collect())
         );
     })
     .await
@@ -153,7 +153,7 @@ async fn qldb_type_sexpr() -> Result<()> {
     create_type_test(get_value_to_insert_sexpr(), |values| {
         assert_eq!(
             values.get("1").unwrap(),
-            &IonValue::SExpr(vec!["list", "of", "strings"].iter().map(|v| v.into()).collect())
+            &IonValue::SExpr(["list", "of", "strings"].iter().map(|v| v.into()).collect())
         );
     })
     .await
@@ -257,7 +257,7 @@ fn get_value_to_insert_sexpr() -> IonValue {
     map.insert(
         "1",
         IonValue::SExpr(
-            vec!["list", "of", "strings"]
+            ["list", "of", "strings"]
                 .iter()
                 .map(|v| IonValue::String(v.to_string()))
                 .collect(),
@@ -3,6 +3,8 @@ use eyre::Result;
 use ion_binary_rs::IonValue;
 use qldb::QldbClient;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use utils::ensure_test_table;
 
 #[async_std::test]
@@ -25,7 +27,7 @@ async fn closing_session_pool() -> Result<()> {
         })
         .await?;
 
-    client.close().await;
+    client.close().await?;
 
     async_std::task::sleep(std::time::Duration::from_millis(100)).await;
 
@@ -46,13 +48,127 @@ async fn closing_session_pool() -> Result<()> {
 
     println!("{:?}", result_b);
 
-    if !result_b.is_err() {
+    if result_b.is_ok() {
         panic!("Close should make the transaction to fail")
     }
 
     Ok(())
 }
 
+#[async_std::test]
+async fn session_pool_stats() -> Result<()> {
+    let client = QldbClient::default("rust-crate-test", 200).await?;
+
+    let test_table = ensure_test_table(&client).await;
+
+    client
+        .read_query(&format!(r#"SELECT COUNT(*) FROM {};"#, test_table))
+        .await?
+        .execute()
+        .await?;
+
+    async_std::task::sleep(std::time::Duration::from_millis(100)).await;
+
+    let stats = client.pool_stats().await?;
+
+    println!("{:?}", stats);
+
+    assert!(stats.active >= 1);
+    assert!(stats.idle >= 1);
+    assert_eq!(stats.waiters, 0);
+    assert_eq!(stats.max_sessions, 200);
+
+    assert_eq!(client.max_sessions().await?, 200);
+    assert!(client.active_sessions().await? >= 1);
+
+    let idle_session_ids = client.idle_session_ids().await?;
+    assert_eq!(idle_session_ids.len(), client.pool_stats().await?.idle);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn client_for_other_ledger() -> Result<()> {
+    let client = QldbClient::default("rust-crate-test", 200).await?;
+
+    let test_table = ensure_test_table(&client).await;
+
+    // There is only one test ledger, but the new client still has its own pool
+    let other_client = client.for_ledger("rust-crate-test")?;
+
+    other_client
+        .read_query(&format!(r#"SELECT COUNT(*) FROM {};"#, test_table))
+        .await?
+        .execute()
+        .await?;
+
+    async_std::task::sleep(std::time::Duration::from_millis(100)).await;
+
+    assert!(other_client.active_sessions().await? >= 1);
+
+    let transaction = other_client.transaction().await?;
+    assert_eq!(transaction.ledger_name(), "rust-crate-test");
+    transaction.rollback().await?;
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn client_health_check() -> Result<()> {
+    let client = QldbClient::default("rust-crate-test", 200).await?;
+
+    client.health_check(Duration::from_secs(10)).await?;
+
+    async_std::task::sleep(std::time::Duration::from_millis(100)).await;
+
+    // The session used for the check goes back to the pool
+    assert!(client.pool_stats().await?.idle >= 1);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn session_pool_fifo_waiters() -> Result<()> {
+    let client = QldbClient::default("rust-crate-test", 1).await?;
+
+    // Takes the only session, so the next ones need to wait
+    let first_transaction = client.transaction().await?;
+
+    let served_order = Arc::new(Mutex::new(vec![]));
+
+    let mut handles = vec![];
+
+    for index in 0..5u64 {
+        let client = client.clone();
+        let served_order = served_order.clone();
+
+        handles.push(async_std::task::spawn(async move {
+            // Makes sure that they arrive in order
+            async_std::task::sleep(Duration::from_millis(index * 50)).await;
+
+            let transaction = client.transaction().await?;
+
+            served_order.lock().unwrap().push(index);
+
+            transaction.rollback().await?;
+
+            Ok::<(), eyre::Report>(())
+        }));
+    }
+
+    async_std::task::sleep(Duration::from_millis(500)).await;
+
+    first_transaction.rollback().await?;
+
+    for handle in handles {
+        handle.await?;
+    }
+
+    assert_eq!(*served_order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+
+    Ok(())
+}
+
 fn get_value_to_insert() -> IonValue {
     let mut map = HashMap::new();
     map.insert("test_column".to_string(), IonValue::String("test_value".to_string()));
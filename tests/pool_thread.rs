@@ -0,0 +1,70 @@
+//! On its own binary, as it counts the threads of the process.
+#![cfg(target_os = "linux")]
+
+use eyre::Result;
+use qldb::QldbClient;
+use rusoto_core::RusotoError;
+use rusoto_qldb_session::{
+    QldbSession, SendCommandError, SendCommandRequest, SendCommandResult, StartSessionResult, StartTransactionResult,
+};
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+struct FakeSession;
+
+#[async_trait::async_trait]
+impl QldbSession for FakeSession {
+    async fn send_command(
+        &self,
+        _input: SendCommandRequest,
+    ) -> Result<SendCommandResult, RusotoError<SendCommandError>> {
+        Ok(SendCommandResult {
+            start_session: Some(StartSessionResult {
+                session_token: Some("session".to_string()),
+                ..Default::default()
+            }),
+            start_transaction: Some(StartTransactionResult {
+                transaction_id: Some("transaction".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+}
+
+fn thread_count() -> usize {
+    std::fs::read_dir("/proc/self/task")
+        .map(|tasks| tasks.count())
+        .unwrap_or(0)
+}
+
+#[test]
+fn pool_thread_stops_once_the_clients_are_dropped() -> Result<()> {
+    futures::executor::block_on(async {
+        let mut client = QldbClient::builder("fake-ledger", 1)
+            .session_client(FakeSession)
+            .build()
+            .await?;
+
+        client.transaction().await?.rollback().await?;
+
+        client.close().await?;
+
+        let running = thread_count();
+
+        drop(client);
+
+        let started_on = Instant::now();
+
+        while thread_count() >= running {
+            assert!(
+                started_on.elapsed() < Duration::from_secs(1),
+                "The session pool thread is still running"
+            );
+
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        Ok(())
+    })
+}
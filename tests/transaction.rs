@@ -6,6 +6,7 @@ use qldb::QldbError::SendCommandError;
 use rusoto_core::RusotoError::Service;
 use rusoto_qldb_session::SendCommandError::OccConflict;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
 use utils::ensure_test_table;
 
 // In order to check internal steps of this transaction the JS
@@ -174,6 +175,85 @@ async fn qldb_transaction_occ_conflict() -> Result<()> {
     Ok(())
 }
 
+#[async_std::test]
+async fn qldb_transact_occ_conflict_retry() -> Result<()> {
+    let client = QldbClient::default("rust-crate-test", 200).await?;
+
+    let test_table = ensure_test_table(&client).await;
+
+    let attempts = AtomicU32::new(0);
+
+    let future_a = client.transact(|client| {
+        let test_table = test_table.clone();
+        let attempt = attempts.fetch_add(1, Relaxed);
+        async move {
+            client
+                .query(&format!(r#"SELECT COUNT(*) FROM {};"#, test_table))
+                .execute()
+                .await?;
+
+            // Only the first attempt waits for the other transaction
+            if attempt == 0 {
+                async_std::task::sleep(std::time::Duration::from_millis(500)).await;
+            }
+
+            Ok(())
+        }
+    });
+
+    let future_b = client.transaction_within(|client| {
+        let test_table = test_table.clone();
+        async move {
+            async_std::task::sleep(std::time::Duration::from_millis(100)).await;
+
+            client
+                .query(&format!("INSERT INTO {} VALUE ?", test_table))
+                .param(get_value_to_insert())
+                .execute()
+                .await?;
+
+            Ok(())
+        }
+    });
+
+    let result = futures::join!(future_a, future_b);
+
+    result.0?;
+    result.1?;
+
+    assert!(attempts.load(Relaxed) >= 2);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn qldb_transaction_execute_batch() -> Result<()> {
+    let client = QldbClient::default("rust-crate-test", 200).await?;
+
+    let test_table = ensure_test_table(&client).await;
+
+    let insert = format!("INSERT INTO {} VALUE ?", test_table);
+    let select = format!("SELECT * FROM {} WHERE test_column = ?", test_table);
+
+    let results = client
+        .transaction_within(|client| async move {
+            client
+                .execute_batch(&[
+                    (&insert, vec![get_value_to_insert()]),
+                    (&insert, vec![get_value_to_insert()]),
+                    (&select, vec![IonValue::String("test_value".to_string())]),
+                ])
+                .await
+        })
+        .await?;
+
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].len(), 1);
+    assert!(results[2].len() >= 2);
+
+    Ok(())
+}
+
 #[async_std::test]
 async fn qldb_transaction_simple_select() -> Result<()> {
     let client = QldbClient::default("rust-crate-test", 200).await?;
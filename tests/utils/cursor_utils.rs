@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use crate::utils::ensure_test_table;
+use futures::StreamExt;
 use ion_binary_rs::IonValue;
 use qldb::DocumentCollection;
 use qldb::QldbClient;
@@ -63,7 +64,7 @@ pub async fn cursor_800_documents_with_client(client: QldbClient) {
 
             while let Some(values) = cursor.load_more().await.unwrap() {
                 counter += 1;
-                result.extend(values.into_iter());
+                result.extend(values);
             }
 
             assert!(counter > 1);
@@ -92,6 +93,33 @@ pub async fn cursor_800_documents_with_client(client: QldbClient) {
         })
         .await
         .unwrap();
+
+    let table = test_table.clone();
+    let model = documents_model.clone();
+
+    client
+        .transaction_within(|tx| async move {
+            let documents = tx
+                .query(&format!("SELECT * FROM {} WHERE Model = ?", &table))
+                .param(model)
+                .get_cursor()
+                .unwrap()
+                .into_stream();
+            futures::pin_mut!(documents);
+
+            let mut counter: usize = 0;
+
+            while let Some(document) = documents.next().await {
+                document.unwrap();
+                counter += 1;
+            }
+
+            assert_eq!(counter, 800);
+
+            Ok(())
+        })
+        .await
+        .unwrap();
 }
 
 macro_rules! hashmap(